@@ -1,165 +1,1204 @@
+mod dns;
 mod test_target;
 mod http;
+mod proxy;
 mod tcp;
+mod template;
 mod udp;
 
-use std::net::SocketAddr;
+use std::{
+    fmt, io,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
+use futures::{stream, StreamExt};
 use serde::{Deserialize, Serialize};
-use tokio::{
-    io::AsyncWriteExt,
-    net::{TcpStream, UdpSocket},
-};
+use tokio_rustls::rustls::ClientConfig;
 
 use crate::{
-    egress::{EgressGroup, EgressRule},
-    imds,
+    egress::{EgressGroup, EgressRule, Protocol, RuleTimeouts},
+    imds::InstanceMetadata,
+    outcome::RuntimeFailure,
 };
 
-pub(crate) const IMDS_HOST: &str = "169.254.169.254";
+pub use dns::DnsResolution;
+pub use proxy::{load_trusted_ca, parse_no_proxy, parse_proxy_url, redact_proxy_url, ProxyConfig};
+pub use template::{normalize_name, parse_assignment, variables, TemplateVars, UnresolvedVariables, KNOWN_VARIABLES};
+pub use udp::NtpDetails;
+
+/// Address IMDS is served from on every Azure VM.
+pub const IMDS_HOST: &str = "169.254.169.254";
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
 pub enum ConnCheckResult {
     Pass,
+    /// The rule was not checked, for example because its destination could not be built.
+    Skipped,
+    /// The destination was reachable, but slower than the latency threshold for the rule.
+    Warn,
     Fail,
 }
 
+/// The point in a probe where a check stopped making progress.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum ProbeStage {
+    /// Resolving the destination name to addresses.
+    Dns,
+    /// Establishing the TCP connection (or binding/connecting the UDP socket).
+    Connect,
+    /// Performing the TLS handshake.
+    Tls,
+    /// Exchanging application data (HTTP request/response, NTP request/reply).
+    Application,
+}
+
+/// Why a check failed, so results can be grouped by cause instead of by error text.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureReason {
+    /// The resolver answered authoritatively that the name does not exist.
+    #[serde(rename = "dns_nxdomain")]
+    DnsNxDomain,
+    /// The name exists but has no A/AAAA records.
+    DnsNoRecords,
+    /// The resolver did not answer in time.
+    DnsTimeout,
+    /// Any other resolver failure (SERVFAIL, no reachable name servers, malformed replies).
+    DnsFailure,
+    /// The destination (or something in front of it) actively refused the connection.
+    ConnectionRefused,
+    /// The TCP connection could not be established in time, typically a silently dropped SYN.
+    ConnectTimeout,
+    /// The connection was reset or closed by the peer or a middlebox after it was established.
+    ConnectionReset,
+    /// The node has no route to the destination network or host.
+    Unreachable,
+    /// The TLS handshake failed for a reason other than certificate trust.
+    TlsHandshakeFailure,
+    /// The TLS handshake did not complete in time.
+    TlsHandshakeTimeout,
+    /// The server's certificate chain could not be verified, e.g. a TLS-inspecting firewall with a private CA.
+    CertificateUntrusted,
+    /// An HTTP response was received but it came from a firewall or proxy denying the request.
+    HttpDenyStatus,
+    /// A proxy rejected the request, e.g. with `407 Proxy Authentication Required`.
+    ProxyRejected,
+    /// The connection was established but no application-layer response arrived in time.
+    ResponseTimeout,
+    /// A response arrived but was not valid for the protocol being probed.
+    InvalidResponse,
+    /// A private cluster's API server FQDN resolved to an address outside the RFC1918 ranges.
+    PublicAddress,
+    /// The rule itself could not be turned into a probe (bad port, unsupported protocol).
+    InvalidRule,
+    /// Anything that doesn't fit one of the categories above.
+    Other,
+}
+
+impl FailureReason {
+    /// Maps an I/O error kind from the given stage onto a failure reason.
+    pub(crate) fn from_io_kind(stage: ProbeStage, kind: io::ErrorKind) -> Self {
+        match kind {
+            io::ErrorKind::ConnectionRefused => FailureReason::ConnectionRefused,
+            io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::UnexpectedEof => FailureReason::ConnectionReset,
+            io::ErrorKind::NetworkUnreachable | io::ErrorKind::HostUnreachable => FailureReason::Unreachable,
+            io::ErrorKind::TimedOut => match stage {
+                ProbeStage::Dns => FailureReason::DnsTimeout,
+                ProbeStage::Connect => FailureReason::ConnectTimeout,
+                ProbeStage::Tls => FailureReason::TlsHandshakeTimeout,
+                ProbeStage::Application => FailureReason::ResponseTimeout,
+            },
+            _ => FailureReason::Other,
+        }
+    }
+}
+
+/// An error from one of the probes, tagged with the stage it happened in and the reason it failed.
+#[derive(Debug)]
+pub(crate) struct ProbeError {
+    pub stage: ProbeStage,
+    pub reason: FailureReason,
+    pub source: anyhow::Error,
+}
+
+impl ProbeError {
+    pub(crate) fn new(stage: ProbeStage, reason: FailureReason, source: impl Into<anyhow::Error>) -> Self {
+        ProbeError {
+            stage,
+            reason,
+            source: source.into(),
+        }
+    }
+
+    /// Wraps an I/O error, classifying it based on its kind and the stage it came from.
+    pub(crate) fn io(stage: ProbeStage, err: io::Error) -> Self {
+        ProbeError::new(stage, FailureReason::from_io_kind(stage, err.kind()), err)
+    }
+}
+
+impl fmt::Display for ProbeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#}", self.source)
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct EgressGroupResult {
-    pub pass_pct: i8,
-    pub failed_checks: Option<Vec<EgressRuleResult>>,
+    pub name: String,
+    /// Whether the group is enabled in the egress data. Rules in a disabled group are reported as skipped unless
+    /// the audit includes disabled groups.
+    pub enabled: bool,
+    /// Whether the group is egress AKS requires, as opposed to egress for optional features and add-ons.
+    pub required: bool,
+    #[serde(flatten)]
+    pub counts: RuleCounts,
+    /// Results for every rule that was checked, passing or not, in the order the rules are defined.
+    pub rule_results: Vec<EgressRuleResult>,
+}
+
+/// How many rules ended up in each state, for a single group or a whole audit.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct RuleCounts {
+    pub passed: usize,
+    /// Rules that were reachable but slower than their latency threshold. These count towards the pass percentage.
+    pub warned: usize,
+    pub failed: usize,
+    /// Enabled rules that were not checked.
+    pub skipped: usize,
+    /// Rules turned off in the egress data, which are never checked.
+    pub disabled: usize,
+    /// Share of the checked rules (passed, warned and failed) that were reachable, from 0 to 100. An empty set of
+    /// checked rules counts as 100.
+    pub pass_pct: f64,
+}
+
+impl RuleCounts {
+    fn add(&mut self, other: &RuleCounts) {
+        self.passed += other.passed;
+        self.warned += other.warned;
+        self.failed += other.failed;
+        self.skipped += other.skipped;
+        self.disabled += other.disabled;
+    }
+
+    fn update_pass_pct(&mut self) {
+        let reachable = self.passed + self.warned;
+        let checked = reachable + self.failed;
+
+        self.pass_pct = if checked == 0 {
+            100.0
+        } else {
+            reachable as f64 * 100.0 / checked as f64
+        };
+    }
+}
+
+/// Totals across every group in an audit.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct AuditSummary {
+    pub groups: usize,
+    #[serde(flatten)]
+    pub counts: RuleCounts,
+}
+
+impl AuditSummary {
+    pub fn from_results(results: &[EgressGroupResult]) -> Self {
+        let mut counts = RuleCounts::default();
+        results.iter().for_each(|g| counts.add(&g.counts));
+        counts.update_pass_pct();
+
+        AuditSummary {
+            groups: results.len(),
+            counts,
+        }
+    }
+}
+
+/// Identifies the node an audit ran from, so a report can be traced back to the zone and subnet it was collected in.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ReportHeader {
+    pub node: String,
+    pub resource_id: String,
+    pub region: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub zone: Option<String>,
+    pub vm_size: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cloud: Option<String>,
+    pub private_ips: Vec<String>,
+    pub subnets: Vec<String>,
+}
+
+impl From<&InstanceMetadata> for ReportHeader {
+    fn from(metadata: &InstanceMetadata) -> Self {
+        let compute = &metadata.compute;
+
+        ReportHeader {
+            node: compute.node_name().to_string(),
+            resource_id: compute.resource_id.clone(),
+            region: compute.location.clone(),
+            zone: Some(compute.zone.clone()).filter(|z| !z.is_empty()),
+            vm_size: compute.vm_size.clone(),
+            cloud: compute.az_environment.clone(),
+            private_ips: metadata.network.private_ips(),
+            subnets: metadata.network.subnet_prefixes(),
+        }
+    }
+}
+
+impl EgressGroupResult {
+    /// Rules in the group that failed their check.
+    pub fn failed_checks(&self) -> impl Iterator<Item = &EgressRuleResult> {
+        self.rule_results
+            .iter()
+            .filter(|r| r.result == ConnCheckResult::Fail)
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct EgressRuleResult {
     pub name: String,
+    /// The port that was probed. Rules that cover several ports have a result for each of them.
+    pub port: Option<u16>,
     pub result: ConnCheckResult,
     pub err_msg: Option<String>,
+    pub reason: Option<FailureReason>,
+    pub failed_stage: Option<ProbeStage>,
+    pub dns: Option<DnsResolution>,
+    pub http_status: Option<u16>,
+    pub ntp: Option<NtpDetails>,
+    /// The proxy the probe was sent through, if it didn't go direct.
+    pub proxy: Option<String>,
+    /// Why the rule wasn't checked, when the result is `Skipped`.
+    pub skip_reason: Option<SkipReason>,
+    pub timings: ProbeTimings,
+}
+
+/// Why a rule was left out of an audit without being probed.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum SkipReason {
+    /// The group the rule belongs to is disabled in the egress data.
+    Disabled,
+    /// The rule's destination uses a template variable that has no value.
+    UnresolvedVariable,
+    /// The audit is for a private cluster and the rule is only needed by public ones.
+    NotRequiredPrivate,
+    /// The rule is for a UDP port with no application-layer probe, so only the route to it could be checked.
+    Unverifiable,
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SkipReason::Disabled => write!(f, "disabled"),
+            SkipReason::UnresolvedVariable => write!(f, "unresolved variable"),
+            SkipReason::NotRequiredPrivate => write!(f, "not required for private clusters"),
+            SkipReason::Unverifiable => write!(f, "UDP reachability not verifiable"),
+        }
+    }
+}
+
+/// How long each stage of a probe took, in milliseconds. Stages that were never reached are left empty.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct ProbeTimings {
+    pub dns_ms: Option<f64>,
+    pub connect_ms: Option<f64>,
+    pub tls_ms: Option<f64>,
+    /// Time from sending the application-layer request to receiving the first byte of the response.
+    pub first_byte_ms: Option<f64>,
+}
+
+impl ProbeTimings {
+    /// Sum of every stage that was recorded.
+    pub fn total_ms(&self) -> f64 {
+        [self.dns_ms, self.connect_ms, self.tls_ms, self.first_byte_ms]
+            .iter()
+            .flatten()
+            .sum()
+    }
+}
+
+/// Milliseconds elapsed since `start`, with sub-millisecond precision.
+pub(crate) fn elapsed_ms(start: Instant) -> f64 {
+    start.elapsed().as_secs_f64() * 1000.0
+}
+
+impl EgressRuleResult {
+    /// A passing result for a rule, which the other results are built on.
+    pub(crate) fn pass(name: &str) -> Self {
+        EgressRuleResult {
+            name: name.to_string(),
+            port: None,
+            result: ConnCheckResult::Pass,
+            err_msg: None,
+            reason: None,
+            failed_stage: None,
+            dns: None,
+            http_status: None,
+            ntp: None,
+            proxy: None,
+            skip_reason: None,
+            timings: ProbeTimings::default(),
+        }
+    }
+
+    fn fail(name: &str, reason: FailureReason, err_msg: String) -> Self {
+        EgressRuleResult {
+            name: name.to_string(),
+            port: None,
+            result: ConnCheckResult::Fail,
+            err_msg: Some(err_msg),
+            reason: Some(reason),
+            failed_stage: None,
+            dns: None,
+            http_status: None,
+            ntp: None,
+            proxy: None,
+            skip_reason: None,
+            timings: ProbeTimings::default(),
+        }
+    }
+
+    fn skipped(name: &str, reason: SkipReason, err_msg: String) -> Self {
+        EgressRuleResult {
+            result: ConnCheckResult::Skipped,
+            err_msg: Some(err_msg),
+            skip_reason: Some(reason),
+            ..EgressRuleResult::pass(name)
+        }
+    }
+
+    fn probe_failed(name: &str, err: ProbeError) -> Self {
+        EgressRuleResult {
+            failed_stage: Some(err.stage),
+            ..EgressRuleResult::fail(name, err.reason, err.to_string())
+        }
+    }
+
+    /// Downgrades a pass to a warning when the total probe time went over `threshold`.
+    fn check_latency(self, threshold: Option<Duration>) -> Self {
+        match threshold {
+            Some(threshold) if self.result == ConnCheckResult::Pass => {
+                let threshold_ms = threshold.as_secs_f64() * 1000.0;
+                let total_ms = self.timings.total_ms();
+
+                if total_ms > threshold_ms {
+                    EgressRuleResult {
+                        result: ConnCheckResult::Warn,
+                        err_msg: Some(format!(
+                            "probe took {:.1}ms, over the latency threshold of {:.1}ms",
+                            total_ms, threshold_ms
+                        )),
+                        ..self
+                    }
+                } else {
+                    self
+                }
+            }
+            _ => self,
+        }
+    }
+}
+
+/// Settings that control how an audit is carried out.
+#[derive(Clone, Debug)]
+pub struct AuditOptions {
+    /// Maximum number of probes in flight at any one time, across all groups.
+    pub concurrency: usize,
+    /// Timeouts applied to every probe unless a rule overrides them.
+    pub timeouts: ProbeTimeouts,
+    /// Total probe time above which a pass is reported as a warning, unless a rule sets its own threshold.
+    pub latency_threshold: Option<Duration>,
+    /// Proxy that HTTP(S) and TCP probes are sent through, matching the cluster's `httpProxyConfig`.
+    pub proxy: ProxyConfig,
+    /// Check disabled groups and rules as well, instead of skipping them.
+    pub include_disabled: bool,
+    /// Template variables supplied by the user. These take precedence over the values the audit works out itself,
+    /// such as the region from IMDS.
+    pub vars: TemplateVars,
+    /// Audit a private cluster: skip rules that aren't `requiredPrivate` and check that the API server FQDN
+    /// resolves to a private address.
+    pub private_cluster: bool,
+}
+
+impl Default for AuditOptions {
+    fn default() -> Self {
+        AuditOptions {
+            concurrency: DEFAULT_CONCURRENCY,
+            timeouts: ProbeTimeouts::default(),
+            latency_threshold: None,
+            proxy: ProxyConfig::default(),
+            include_disabled: false,
+            vars: TemplateVars::default(),
+            private_cluster: false,
+        }
+    }
+}
+
+/// How long each stage of a probe may take before it's reported as a timeout.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ProbeTimeouts {
+    /// Establishing a TCP connection, per resolved address.
+    pub connect: Duration,
+    /// Completing the TLS handshake once connected.
+    pub handshake: Duration,
+    /// Receiving an application-layer response (HTTP status line, NTP reply) once the request is sent.
+    pub response: Duration,
+}
+
+impl Default for ProbeTimeouts {
+    fn default() -> Self {
+        ProbeTimeouts {
+            connect: Duration::from_secs(5),
+            handshake: Duration::from_secs(5),
+            response: Duration::from_secs(10),
+        }
+    }
+}
+
+impl ProbeTimeouts {
+    /// Applies any overrides set on a rule on top of these timeouts.
+    pub fn with_overrides(&self, overrides: Option<&RuleTimeouts>) -> ProbeTimeouts {
+        let Some(overrides) = overrides else {
+            return *self;
+        };
+
+        ProbeTimeouts {
+            connect: overrides.connect_ms.map(Duration::from_millis).unwrap_or(self.connect),
+            handshake: overrides.handshake_ms.map(Duration::from_millis).unwrap_or(self.handshake),
+            response: overrides.response_ms.map(Duration::from_millis).unwrap_or(self.response),
+        }
+    }
 }
 
-#[tracing::instrument(skip(egress_groups))]
+/// Default cap on concurrent probes. High enough that a handful of blackholed destinations don't serialize the
+/// audit, low enough not to look like a port scan to whatever is inspecting egress.
+pub const DEFAULT_CONCURRENCY: usize = 16;
+
+// the options are left out of the span since the proxy URLs in them can hold credentials
+#[tracing::instrument(skip(egress_groups, metadata, options), fields(concurrency = options.concurrency))]
 pub async fn check_connectivity(
-    egress_groups: &Vec<EgressGroup>,
+    egress_groups: &[EgressGroup],
     ccp_fqdn: &str,
+    metadata: &InstanceMetadata,
+    options: &AuditOptions,
 ) -> Result<Vec<EgressGroupResult>> {
     tracing::debug!("Beginning connectivity checks...");
-    let vm_region = imds::get_region(IMDS_HOST).await?; // grab region for use in URLs
-    let mut res: Vec<EgressGroupResult> = Vec::new();
+    let resolver = dns::DnsResolver::from_system_conf()
+        .with_context(|| RuntimeFailure(String::from("failed to set up the DNS resolver from the system config")))?;
+    let vars = template_vars(ccp_fqdn, &metadata.compute.location, &options.vars);
 
-    // TODO: Make this spawn new threads so we can parallelize these tests
-    for group in egress_groups {
-        audit_group(&group, &mut res, ccp_fqdn, vm_region.as_str()).await;
+    let mut results = Vec::new();
+    if options.private_cluster {
+        let group = EgressGroup {
+            enabled: true,
+            name: String::from(PRIVATE_CLUSTER_GROUP),
+            rules: Vec::new(),
+            required_group: true,
+            cloud: None,
+        };
+        results.push(summarize_group(&group, vec![check_private_ccp(ccp_fqdn, &resolver).await]));
     }
+    results.extend(audit_groups(egress_groups, &vars, &resolver, options).await);
 
-    Ok(res)
+    Ok(results)
 }
 
-#[tracing::instrument(skip(group, res))]
-async fn audit_group(
-    group: &EgressGroup,
-    res: &mut Vec<EgressGroupResult>,
-    ccp: &str,
-    vm_region: &str,
-) {
-    let mut rule_res_vec: Vec<EgressRuleResult> = Vec::new();
+/// Name of the group reporting the checks `--private-cluster` adds on top of the egress data.
+pub const PRIVATE_CLUSTER_GROUP: &str = "private-cluster";
 
-    for rule in group.rules.clone() {
-        if rule.rule_enabled == false {
-            continue;
-        } else {
-            let dest = self::test_target::build_conn_string(&rule, ccp, vm_region).await.unwrap();
-            let addr = dest.parse::<SocketAddr>();
-
-            if addr.is_err() {
-                let addr_err = addr.unwrap_err();
-                log::warn!("Failed to parse address: {}",addr_err.to_string());
-                let rule_res = EgressRuleResult {
-                    name: rule.name,
-                    result: ConnCheckResult::Fail,
-                    err_msg: Some(addr_err.to_string()),
-                };
-                rule_res_vec.push(rule_res);
-                continue;
+/// Checks that the API server FQDN of a private cluster resolves to RFC1918 addresses only, which it does when the
+/// node's VNet is linked to the cluster's `privatelink.<region>.azmk8s.io` private DNS zone.
+async fn check_private_ccp(ccp_fqdn: &str, resolver: &dns::DnsResolver) -> EgressRuleResult {
+    let name = "api-server-private-address";
+    let dns_start = Instant::now();
+    let resolved = resolver.resolve(ccp_fqdn).await;
+    let timings = ProbeTimings {
+        dns_ms: Some(elapsed_ms(dns_start)),
+        ..ProbeTimings::default()
+    };
+
+    let result = match resolved {
+        Ok(resolution) => {
+            let public: Vec<String> = resolution
+                .addresses
+                .iter()
+                .filter(|a| !is_rfc1918(a))
+                .map(|a| a.to_string())
+                .collect();
+            let result = match public.is_empty() {
+                true => EgressRuleResult::pass(name),
+                false => EgressRuleResult {
+                    failed_stage: Some(ProbeStage::Dns),
+                    ..EgressRuleResult::fail(
+                        name,
+                        FailureReason::PublicAddress,
+                        format!(
+                            "{} resolved to {}, which is not an RFC1918 address. Check that the private DNS zone of the cluster is linked to the node's VNet.",
+                            ccp_fqdn,
+                            public.join(", ")
+                        ),
+                    )
+                },
+            };
+            EgressRuleResult {
+                dns: Some(resolution),
+                ..result
             }
+        }
+        Err((resolution, e)) => EgressRuleResult {
+            dns: Some(resolution),
+            ..EgressRuleResult::probe_failed(name, e)
+        },
+    };
 
-            match rule.protocol.as_str() {
-                "udp" => {
-                    let sock = UdpSocket::bind("0.0.0.0:0").await.unwrap();
-                    let stream = sock.connect(addr.unwrap()).await;
-
-                    if stream.is_err() {
-                        // handle connectivity error/fail
-                        let e = stream.err().unwrap();
-                        let rule_res = EgressRuleResult {
-                            name: rule.name,
-                            result: ConnCheckResult::Fail,
-                            err_msg: Some(e.to_string()),
-                        };
-                        let _ = &rule_res_vec.push(rule_res);
-                    } else {
-                        // test passed
-                        let rule_res = EgressRuleResult {
-                            name: rule.name,
-                            result: ConnCheckResult::Pass,
-                            err_msg: None,
-                        };
-                        let _ = &rule_res_vec.push(rule_res);
-                    }
-                }
-                "tcp" => {
-                    let stream = TcpStream::connect(addr.unwrap()).await;
-
-                    match stream {
-                        Err(e) => {
-                            let rule_res = EgressRuleResult {
-                                name: rule.name,
-                                result: ConnCheckResult::Fail,
-                                err_msg: Some(e.to_string()),
-                            };
-                            rule_res_vec.push(rule_res);
-                        }
-                        Ok(mut c) => {
-                            // TODO: Should we even care about successful results here or just assume any rules not marked as a fail
-                            // are a pass?
-                            let rule_res = EgressRuleResult {
-                                name: rule.name,
-                                result: ConnCheckResult::Pass,
-                                err_msg: None,
-                            };
-                            rule_res_vec.push(rule_res);
-
-                            c.shutdown().await.unwrap();
-                        }
-                    }
-                }
+    EgressRuleResult { timings, ..result }
+}
+
+fn is_rfc1918(addr: &std::net::IpAddr) -> bool {
+    match addr {
+        std::net::IpAddr::V4(v4) => v4.is_private(),
+        std::net::IpAddr::V6(_) => false,
+    }
+}
 
-                "https" | "http" => {
-                    todo!()
+/// Collects the variables available to rule destinations: the region from IMDS (as both `region` and `location`),
+/// the CCP FQDN and the CCP ID taken from its first label, with anything the user supplied layered on top.
+fn template_vars(ccp_fqdn: &str, vm_region: &str, user_vars: &TemplateVars) -> TemplateVars {
+    let mut vars = TemplateVars::new();
+    vars.set("region", vm_region);
+    vars.set("location", vm_region);
+    vars.set("ccp-fqdn", ccp_fqdn);
+    if ccp_fqdn.parse::<std::net::IpAddr>().is_err() {
+        if let Some(ccp_id) = ccp_fqdn.split('.').next().filter(|id| !id.is_empty()) {
+            vars.set("ccp-id", ccp_id);
+        }
+    }
+    vars.extend(user_vars);
+
+    vars
+}
+
+/// Probes every enabled rule across all groups concurrently, bounded by `options.concurrency`.
+///
+/// Rules in disabled groups are reported as skipped without being probed, and disabled rules are left out entirely,
+/// unless `options.include_disabled` is set. Results come back in the same order as the groups and rules were
+/// supplied, regardless of which probes finish first.
+async fn audit_groups(
+    egress_groups: &[EgressGroup],
+    vars: &TemplateVars,
+    resolver: &dns::DnsResolver,
+    options: &AuditOptions,
+) -> Vec<EgressGroupResult> {
+    let include_disabled = options.include_disabled;
+    // rules covering several ports are probed once per port
+    let rules: Vec<(usize, &EgressRule, u16)> = egress_groups
+        .iter()
+        .enumerate()
+        .flat_map(|(idx, group)| {
+            group
+                .rules
+                .iter()
+                .filter(move |r| r.rule_enabled || include_disabled)
+                .flat_map(move |r| r.port.ports().map(move |port| (idx, r, port)))
+        })
+        .collect();
+    tracing::debug!(
+        "Auditing {} rules across {} groups with up to {} probes in flight",
+        rules.len(),
+        egress_groups.len(),
+        options.concurrency
+    );
+
+    let tls_config = http::tls_config(&options.proxy.trusted_ca);
+    let tls_config = &tls_config;
+
+    // `buffered` keeps at most `concurrency` probes running and yields them in input order
+    let rule_results: Vec<(usize, EgressRuleResult)> = stream::iter(rules)
+        .map(|(idx, rule, port)| async move {
+            let group = &egress_groups[idx];
+            let rule_res = if !group.enabled && !include_disabled {
+                tracing::debug!("Skipping rule {} since group {} is disabled", rule.name, group.name);
+                let msg = format!("group {} is disabled in the egress data", group.name);
+                EgressRuleResult::skipped(&rule.name, SkipReason::Disabled, msg)
+            } else if options.private_cluster && !rule.required_private {
+                tracing::debug!("Skipping rule {} since it isn't required for private clusters", rule.name);
+                let msg = String::from("not required for private clusters");
+                EgressRuleResult::skipped(&rule.name, SkipReason::NotRequiredPrivate, msg)
+            } else {
+                audit_rule(rule, port, vars, resolver, options, tls_config).await
+            };
+
+            (idx, EgressRuleResult { port: Some(port), ..rule_res })
+        })
+        .buffered(options.concurrency.max(1))
+        .collect()
+        .await;
+
+    let mut grouped: Vec<Vec<EgressRuleResult>> = vec![Vec::new(); egress_groups.len()];
+    for (idx, rule_res) in rule_results {
+        grouped[idx].push(rule_res);
+    }
+
+    egress_groups
+        .iter()
+        .zip(grouped)
+        .map(|(group, rule_res_vec)| summarize_group(group, rule_res_vec))
+        .collect()
+}
+
+/// Rolls the individual rule results for a group up into a group result.
+///
+/// Disabled rules never get a result, so they're counted from the group definition instead.
+fn summarize_group(group: &EgressGroup, rule_res_vec: Vec<EgressRuleResult>) -> EgressGroupResult {
+    // disabled rules that were included in the audit have results of their own
+    let checked_names: Vec<&str> = rule_res_vec.iter().map(|r| r.name.as_str()).collect();
+    let mut counts = RuleCounts {
+        disabled: group
+            .rules
+            .iter()
+            .filter(|r| !r.rule_enabled && !checked_names.contains(&r.name.as_str()))
+            .count(),
+        ..RuleCounts::default()
+    };
+    for rule_res in &rule_res_vec {
+        match rule_res.result {
+            ConnCheckResult::Pass => counts.passed += 1,
+            ConnCheckResult::Warn => counts.warned += 1,
+            ConnCheckResult::Fail => counts.failed += 1,
+            ConnCheckResult::Skipped => counts.skipped += 1,
+        }
+    }
+    counts.update_pass_pct();
+
+    EgressGroupResult {
+        name: group.name.clone(),
+        enabled: group.enabled,
+        required: group.required_group,
+        counts,
+        rule_results: rule_res_vec,
+    }
+}
+
+/// Checks a single rule, recording how long each stage took and flagging passes that were slower than the rule's
+/// latency threshold.
+async fn audit_rule(
+    rule: &EgressRule,
+    port: u16,
+    vars: &TemplateVars,
+    resolver: &dns::DnsResolver,
+    options: &AuditOptions,
+    tls_config: &Arc<ClientConfig>,
+) -> EgressRuleResult {
+    let mut timings = ProbeTimings::default();
+    let rule_res = probe_rule(rule, port, vars, resolver, options, tls_config, &mut timings).await;
+    let threshold = rule
+        .latency_threshold_ms
+        .map(Duration::from_millis)
+        .or(options.latency_threshold);
+
+    EgressRuleResult { timings, ..rule_res }.check_latency(threshold)
+}
+
+/// Resolves the rule's destination, then runs the probe that matches the rule's protocol against the resolved
+/// addresses.
+///
+/// When the destination is reached through a proxy, the proxy's name is resolved instead since that's the only
+/// lookup the node makes itself.
+async fn probe_rule(
+    rule: &EgressRule,
+    port: u16,
+    vars: &TemplateVars,
+    resolver: &dns::DnsResolver,
+    options: &AuditOptions,
+    tls_config: &Arc<ClientConfig>,
+    timings: &mut ProbeTimings,
+) -> EgressRuleResult {
+    let timeouts = options.timeouts.with_overrides(rule.timeouts.as_ref());
+    let host = match self::test_target::build_target_host(rule, vars) {
+        Ok(host) => host,
+        Err(unresolved) => {
+            log::warn!("Skipping rule {}: {}", rule.name, unresolved);
+            return EgressRuleResult::skipped(
+                &rule.name,
+                SkipReason::UnresolvedVariable,
+                format!("skipped: {}", unresolved),
+            );
+        }
+    };
+    let route = proxy::Route {
+        proxy: options.proxy.proxy_for(rule.protocol, &host),
+        tls_config: tls_config.clone(),
+    };
+    let proxy_name = route.proxy.map(proxy::redact_proxy_url);
+    let (lookup_host, lookup_port) = match route.proxy {
+        Some(p) => proxy::proxy_host_port(p),
+        None => (host.clone(), port),
+    };
+
+    let dns_start = Instant::now();
+    let resolved = resolver.resolve(&lookup_host).await;
+    timings.dns_ms = Some(elapsed_ms(dns_start));
+
+    let resolution = match resolved {
+        Ok(resolution) => resolution,
+        Err((resolution, e)) => {
+            log::warn!("DNS resolution failed for rule {}: {}", rule.name, e);
+            return EgressRuleResult {
+                dns: Some(resolution),
+                proxy: proxy_name,
+                ..EgressRuleResult::probe_failed(&rule.name, e)
+            };
+        }
+    };
+    let addrs: Vec<SocketAddr> = resolution
+        .addresses
+        .iter()
+        .map(|ip| SocketAddr::new(*ip, lookup_port))
+        .collect();
+
+    let rule_res = match rule.protocol {
+        Protocol::Udp if port == udp::NTP_PORT => match udp::probe_ntp(&addrs, timeouts.response, timings).await {
+            Ok(details) => {
+                log::debug!(
+                    "{} answered NTP request: stratum {}, offset {:.3}ms",
+                    details.server,
+                    details.stratum,
+                    details.offset_ms
+                );
+                EgressRuleResult {
+                    ntp: Some(details),
+                    ..EgressRuleResult::pass(&rule.name)
                 }
-                _ => {
-                    todo!()
+            }
+            Err(e) => EgressRuleResult::probe_failed(&rule.name, e),
+        },
+        // connecting a UDP socket doesn't put anything on the wire, so for UDP protocols without an
+        // application-layer probe a firewall dropping the traffic can't be told apart from a silent server. A
+        // missing route still fails, anything else is reported as unverified rather than passed.
+        Protocol::Udp => match udp::probe_route(&addrs).await {
+            Ok(addr) => EgressRuleResult::skipped(
+                &rule.name,
+                SkipReason::Unverifiable,
+                format!("UDP reachability not verifiable, only the route to {} was checked", addr),
+            ),
+            Err(e) => EgressRuleResult::probe_failed(&rule.name, e),
+        },
+        Protocol::Tcp if route.proxy.is_some() => match proxy::probe_tunnel(&route, &addrs, &host, port, &timeouts, timings).await {
+            Ok(_) => EgressRuleResult::pass(&rule.name),
+            Err(e) => EgressRuleResult::probe_failed(&rule.name, e),
+        },
+        Protocol::Tcp => match tcp::probe(&addrs, timeouts.connect, timings).await {
+            Ok(_) => EgressRuleResult::pass(&rule.name),
+            Err(e) => EgressRuleResult::probe_failed(&rule.name, e),
+        },
+        Protocol::Https | Protocol::Http => match http::probe(&host, &addrs, port, rule.protocol == Protocol::Https, &route, &timeouts, timings)
+            .await
+        {
+            Ok(probe) => {
+                log::debug!("{} responded with HTTP {} from {}", host, probe.status_code, probe.peer_addr);
+                let res = match probe.rejection {
+                    Some(reason) => EgressRuleResult {
+                        failed_stage: Some(ProbeStage::Application),
+                        ..EgressRuleResult::fail(
+                            &rule.name,
+                            reason,
+                            format!("request was rejected with HTTP {} from {}", probe.status_code, probe.peer_addr),
+                        )
+                    },
+                    None => EgressRuleResult::pass(&rule.name),
+                };
+
+                EgressRuleResult {
+                    http_status: Some(probe.status_code),
+                    ..res
                 }
             }
+            Err(e) => EgressRuleResult::probe_failed(&rule.name, e),
+        },
+    };
+
+    EgressRuleResult {
+        dns: Some(resolution),
+        proxy: proxy_name,
+        ..rule_res
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::egress::PortSpec;
+
+    #[test]
+    fn failure_reasons_should_serialize_as_snake_case() {
+        assert_eq!("\"dns_nxdomain\"", serde_json::to_string(&FailureReason::DnsNxDomain).unwrap());
+        assert_eq!("\"connect_timeout\"", serde_json::to_string(&FailureReason::ConnectTimeout).unwrap());
+        assert_eq!(
+            "\"certificate_untrusted\"",
+            serde_json::to_string(&FailureReason::CertificateUntrusted).unwrap()
+        );
+    }
+
+    #[test]
+    fn io_errors_should_map_to_reasons_by_stage() {
+        assert_eq!(
+            FailureReason::ConnectionRefused,
+            FailureReason::from_io_kind(ProbeStage::Connect, io::ErrorKind::ConnectionRefused)
+        );
+        assert_eq!(
+            FailureReason::ConnectionReset,
+            FailureReason::from_io_kind(ProbeStage::Tls, io::ErrorKind::UnexpectedEof)
+        );
+        assert_eq!(
+            FailureReason::ConnectTimeout,
+            FailureReason::from_io_kind(ProbeStage::Connect, io::ErrorKind::TimedOut)
+        );
+        assert_eq!(
+            FailureReason::ResponseTimeout,
+            FailureReason::from_io_kind(ProbeStage::Application, io::ErrorKind::TimedOut)
+        );
+    }
+
+    #[test]
+    fn rule_overrides_should_replace_only_the_values_they_set() {
+        let defaults = ProbeTimeouts::default();
+        let overrides = RuleTimeouts {
+            connect_ms: Some(750),
+            ..RuleTimeouts::default()
+        };
+
+        let merged = defaults.with_overrides(Some(&overrides));
+
+        assert_eq!(Duration::from_millis(750), merged.connect);
+        assert_eq!(defaults.handshake, merged.handshake);
+        assert_eq!(defaults.response, merged.response);
+        assert_eq!(defaults, defaults.with_overrides(None));
+    }
+
+    #[test]
+    fn slow_pass_should_become_a_warning() {
+        let res = EgressRuleResult {
+            timings: ProbeTimings {
+                dns_ms: Some(20.0),
+                connect_ms: Some(300.0),
+                ..ProbeTimings::default()
+            },
+            ..EgressRuleResult::pass("slow")
+        };
+
+        assert_eq!(320.0, res.timings.total_ms());
+        assert_eq!(ConnCheckResult::Pass, res.clone().check_latency(None).result);
+        assert_eq!(
+            ConnCheckResult::Pass,
+            res.clone().check_latency(Some(Duration::from_millis(500))).result
+        );
+
+        let warned = res.check_latency(Some(Duration::from_millis(250)));
+        assert_eq!(ConnCheckResult::Warn, warned.result);
+        assert!(warned.err_msg.is_some());
+        assert_eq!(None, warned.reason);
+
+        let failed = EgressRuleResult::fail("down", FailureReason::ConnectTimeout, String::from("timed out"));
+        assert_eq!(
+            ConnCheckResult::Fail,
+            failed.check_latency(Some(Duration::from_millis(1))).result
+        );
+    }
+
+    fn test_resolver() -> dns::DnsResolver {
+        dns::DnsResolver::new(
+            trust_dns_resolver::config::ResolverConfig::new(),
+            trust_dns_resolver::config::ResolverOpts::default(),
+        )
+        .unwrap()
+    }
+
+    fn test_vars() -> TemplateVars {
+        template_vars("ccp", "eastus2", &TemplateVars::new())
+    }
+
+    fn tcp_rule(name: &str, port: u16) -> EgressRule {
+        EgressRule {
+            name: name.to_string(),
+            dst: String::from("127.0.0.1"),
+            protocol: Protocol::Tcp,
+            port: PortSpec::from(port),
+            description: String::new(),
+            required_private: true,
+            rule_enabled: true,
+            timeouts: None,
+            latency_threshold_ms: None,
+            origin: Default::default(),
         }
     }
 
-    let fail_count = rule_res_vec
-        .clone()
-        .into_iter()
-        .filter(|r| r.result == ConnCheckResult::Fail)
-        .collect::<Vec<EgressRuleResult>>()
-        .len();
+    #[test]
+    fn group_counts_should_exclude_disabled_rules_from_pass_pct() {
+        let group = EgressGroup {
+            enabled: true,
+            name: String::from("core"),
+            required_group: true,
+            cloud: None,
+            rules: vec![
+                tcp_rule("a", 1),
+                tcp_rule("b", 2),
+                tcp_rule("c", 3),
+                tcp_rule("d", 4),
+                EgressRule {
+                    rule_enabled: false,
+                    ..tcp_rule("e", 5)
+                },
+            ],
+        };
+        let failed = EgressRuleResult::fail("c", FailureReason::ConnectionRefused, String::from("refused"));
+        let skipped = EgressRuleResult {
+            result: ConnCheckResult::Skipped,
+            ..EgressRuleResult::pass("d")
+        };
+        let warned = EgressRuleResult {
+            result: ConnCheckResult::Warn,
+            ..EgressRuleResult::pass("b")
+        };
+
+        let res = summarize_group(&group, vec![EgressRuleResult::pass("a"), warned, failed, skipped]);
+
+        assert_eq!("core", res.name);
+        assert_eq!(
+            RuleCounts {
+                passed: 1,
+                warned: 1,
+                failed: 1,
+                skipped: 1,
+                disabled: 1,
+                pass_pct: 200.0 / 3.0,
+            },
+            res.counts
+        );
+
+        let summary = AuditSummary::from_results(&[res.clone(), res]);
+        assert_eq!(2, summary.groups);
+        assert_eq!(2, summary.counts.failed);
+        assert_eq!(2, summary.counts.disabled);
+        assert_eq!(200.0 / 3.0, summary.counts.pass_pct);
+        assert_eq!(100.0, AuditSummary::from_results(&[]).counts.pass_pct);
+    }
+
+    #[tokio::test]
+    async fn disabled_groups_should_be_skipped_unless_included() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let open_port = listener.local_addr().unwrap().port();
+        let groups = vec![EgressGroup {
+            enabled: false,
+            name: String::from("staged"),
+            required_group: true,
+            cloud: None,
+            rules: vec![
+                tcp_rule("open", open_port),
+                EgressRule {
+                    rule_enabled: false,
+                    ..tcp_rule("disabled", open_port)
+                },
+            ],
+        }];
+        let resolver = test_resolver();
+
+        let res = audit_groups(&groups, &test_vars(), &resolver, &AuditOptions::default()).await;
+        let rule_res = &res[0].rule_results;
+        assert_eq!(1, rule_res.len());
+        assert_eq!(ConnCheckResult::Skipped, rule_res[0].result);
+        assert_eq!(Some(SkipReason::Disabled), rule_res[0].skip_reason);
+        assert_eq!((1, 1), (res[0].counts.skipped, res[0].counts.disabled));
+
+        let options = AuditOptions {
+            include_disabled: true,
+            ..AuditOptions::default()
+        };
+        let res = audit_groups(&groups, &test_vars(), &resolver, &options).await;
+        let results: Vec<ConnCheckResult> = res[0].rule_results.iter().map(|r| r.result).collect();
+        assert_eq!(vec![ConnCheckResult::Pass, ConnCheckResult::Pass], results);
+        assert_eq!((2, 0, 0), (res[0].counts.passed, res[0].counts.skipped, res[0].counts.disabled));
+    }
+
+    #[tokio::test]
+    async fn private_cluster_should_skip_public_only_rules_and_check_the_ccp_address() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let open_port = listener.local_addr().unwrap().port();
+        let groups = vec![EgressGroup {
+            enabled: true,
+            name: String::from("core"),
+            required_group: true,
+            cloud: None,
+            rules: vec![
+                tcp_rule("private", open_port),
+                EgressRule {
+                    required_private: false,
+                    ..tcp_rule("public-only", open_port)
+                },
+            ],
+        }];
+        let resolver = test_resolver();
+        let options = AuditOptions {
+            private_cluster: true,
+            ..AuditOptions::default()
+        };
 
-    let passed_percent = (fail_count / group.rules.len()) as i8;
+        let res = audit_groups(&groups, &test_vars(), &resolver, &options).await;
+        let rule_res = &res[0].rule_results;
+        assert_eq!(ConnCheckResult::Pass, rule_res[0].result);
+        assert_eq!(Some(SkipReason::NotRequiredPrivate), rule_res[1].skip_reason);
+        assert_eq!((1, 1), (res[0].counts.passed, res[0].counts.skipped));
 
-    res.push(EgressGroupResult {
-        pass_pct: passed_percent,
-        failed_checks: Some(
-            rule_res_vec
-                .into_iter()
-                .filter(|r_res| r_res.result == ConnCheckResult::Fail)
-                .collect::<Vec<EgressRuleResult>>(),
-        ),
-    })
-}
\ No newline at end of file
+        assert_eq!(ConnCheckResult::Pass, check_private_ccp("10.224.0.4", &resolver).await.result);
+        let public = check_private_ccp("20.62.1.1", &resolver).await;
+        assert_eq!(Some(FailureReason::PublicAddress), public.reason);
+        assert_eq!(Some(ProbeStage::Dns), public.failed_stage);
+    }
+
+    #[tokio::test]
+    async fn rules_with_several_ports_should_be_probed_on_each() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let open_port = listener.local_addr().unwrap().port();
+        let closed_port = {
+            let l = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            l.local_addr().unwrap().port()
+        };
+        let groups = vec![EgressGroup {
+            enabled: true,
+            name: String::from("core"),
+            required_group: true,
+            cloud: None,
+            rules: vec![EgressRule {
+                port: PortSpec::parse(&format!("{},{}", open_port, closed_port)).unwrap(),
+                ..tcp_rule("api-server", open_port)
+            }],
+        }];
+        let resolver = test_resolver();
+
+        let res = audit_groups(&groups, &test_vars(), &resolver, &AuditOptions::default()).await;
+        let results: Vec<(Option<u16>, ConnCheckResult)> =
+            res[0].rule_results.iter().map(|r| (r.port, r.result)).collect();
+
+        assert_eq!(
+            vec![(Some(open_port), ConnCheckResult::Pass), (Some(closed_port), ConnCheckResult::Fail)],
+            results
+        );
+        assert_eq!(0, res[0].counts.disabled);
+    }
+
+    #[tokio::test]
+    async fn udp_rules_without_a_probe_should_not_pass() {
+        let groups = vec![EgressGroup {
+            enabled: true,
+            name: String::from("tunnel"),
+            required_group: true,
+            cloud: None,
+            rules: vec![EgressRule {
+                protocol: Protocol::Udp,
+                ..tcp_rule("api-server-udp-1194", 1194)
+            }],
+        }];
+
+        let res = audit_groups(&groups, &test_vars(), &test_resolver(), &AuditOptions::default()).await;
+        let rule_res = &res[0].rule_results[0];
+        assert_eq!(ConnCheckResult::Skipped, rule_res.result);
+        assert_eq!(Some(SkipReason::Unverifiable), rule_res.skip_reason);
+        assert_eq!((0, 1), (res[0].counts.passed, res[0].counts.skipped));
+    }
+
+    #[test]
+    fn builtin_vars_should_yield_to_user_vars() {
+        let mut user_vars = TemplateVars::new();
+        user_vars.set("location", "westus3");
+
+        let vars = template_vars("myaks-abc123.hcp.eastus2.azmk8s.io", "eastus2", &user_vars);
+
+        assert_eq!(Some("myaks-abc123"), vars.get("ccp_id"));
+        assert_eq!(Some("eastus2"), vars.get("region"));
+        assert_eq!(Some("westus3"), vars.get("location"));
+        assert_eq!(None, template_vars("10.0.0.1", "eastus2", &user_vars).get("ccp-id"));
+
+        let api_server = EgressRule {
+            dst: String::from("{ccp-fqdn}"),
+            ..tcp_rule("api-server-https-443", 443)
+        };
+        assert_eq!(
+            Ok(String::from("myaks-abc123.hcp.eastus2.azmk8s.io")),
+            test_target::build_target_host(&api_server, &vars)
+        );
+    }
+
+    #[tokio::test]
+    async fn unresolved_variables_should_skip_the_rule() {
+        let groups = vec![EgressGroup {
+            enabled: true,
+            name: String::from("monitor"),
+            required_group: false,
+            cloud: None,
+            rules: vec![EgressRule {
+                dst: String::from("{id}.ods.opinsights.azure.com"),
+                ..tcp_rule("ods", 443)
+            }],
+        }];
+        let resolver = test_resolver();
+
+        let res = audit_groups(&groups, &test_vars(), &resolver, &AuditOptions::default()).await;
+        let rule_res = &res[0].rule_results[0];
+
+        assert_eq!(ConnCheckResult::Skipped, rule_res.result);
+        assert_eq!(Some(SkipReason::UnresolvedVariable), rule_res.skip_reason);
+        assert_eq!(Some("skipped: unresolved variable {id}"), rule_res.err_msg.as_deref());
+        assert_eq!(None, rule_res.dns);
+    }
+
+    #[tokio::test]
+    async fn concurrent_audit_should_keep_rule_order() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let open_port = listener.local_addr().unwrap().port();
+        let closed_port = {
+            let l = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            l.local_addr().unwrap().port()
+        };
+
+        let groups = vec![
+            EgressGroup {
+                enabled: true,
+                name: String::from("first"),
+                required_group: true,
+                cloud: None,
+                rules: vec![tcp_rule("a-closed", closed_port), tcp_rule("b-open", open_port)],
+            },
+            EgressGroup {
+                enabled: true,
+                name: String::from("second"),
+                required_group: true,
+                cloud: None,
+                rules: vec![tcp_rule("c-closed", closed_port), tcp_rule("d-closed", closed_port)],
+            },
+        ];
+        let resolver = test_resolver();
+
+        for concurrency in [1, 8] {
+            let options = AuditOptions {
+                concurrency,
+                ..AuditOptions::default()
+            };
+            let res = audit_groups(&groups, &test_vars(), &resolver, &options).await;
+
+            assert_eq!(2, res.len());
+            let failed: Vec<Vec<String>> = res
+                .iter()
+                .map(|g| g.failed_checks().map(|r| r.name.clone()).collect())
+                .collect();
+            assert_eq!(vec![vec!["a-closed"], vec!["c-closed", "d-closed"]], failed);
+        }
+    }
+}
@@ -0,0 +1,172 @@
+use std::net::IpAddr;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use trust_dns_resolver::{
+    config::{ResolverConfig, ResolverOpts},
+    error::{ResolveError, ResolveErrorKind},
+    proto::{
+        op::ResponseCode,
+        rr::{RData, RecordType},
+    },
+    system_conf, TokioAsyncResolver,
+};
+
+use super::{FailureReason, ProbeError, ProbeStage};
+
+/// Outcome of the DNS stage for a single rule.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct DnsResolution {
+    /// The name that was looked up.
+    pub query: String,
+    /// Addresses returned for the name, in the order the resolver returned them.
+    pub addresses: Vec<IpAddr>,
+    /// Each CNAME target followed on the way to the final answer, starting with the first alias.
+    pub cname_chain: Vec<String>,
+    /// The name servers that answered (or failed to answer) the query.
+    pub resolver: String,
+}
+
+/// Resolver shared by every probe in an audit so lookups go through one configuration and one cache.
+pub(crate) struct DnsResolver {
+    inner: TokioAsyncResolver,
+    description: String,
+}
+
+impl DnsResolver {
+    /// Builds a resolver from the node's `/etc/resolv.conf`, the same configuration workloads on the node would use.
+    pub(crate) fn from_system_conf() -> Result<Self> {
+        let (config, opts) = system_conf::read_system_conf()?;
+
+        Self::new(config, opts)
+    }
+
+    pub(crate) fn new(config: ResolverConfig, opts: ResolverOpts) -> Result<Self> {
+        let mut servers: Vec<String> = Vec::new();
+        for ns in config.name_servers() {
+            let addr = ns.socket_addr.to_string();
+            if !servers.contains(&addr) {
+                servers.push(addr);
+            }
+        }
+
+        let description = if servers.is_empty() {
+            String::from("none configured")
+        } else {
+            servers.join(", ")
+        };
+
+        Ok(DnsResolver {
+            inner: TokioAsyncResolver::tokio(config, opts)?,
+            description,
+        })
+    }
+
+    /// Looks up the A/AAAA records for `host`, following and recording any CNAMEs along the way.
+    ///
+    /// On failure the partially filled in `DnsResolution` is returned alongside the error so callers can still report
+    /// which name and resolver were involved.
+    pub(crate) async fn resolve(&self, host: &str) -> Result<DnsResolution, (DnsResolution, ProbeError)> {
+        let mut resolution = DnsResolution {
+            query: host.to_string(),
+            resolver: self.description.clone(),
+            ..Default::default()
+        };
+
+        match self.inner.lookup_ip(host).await {
+            Ok(lookup) => {
+                resolution.addresses = lookup.iter().collect();
+                resolution.cname_chain = lookup
+                    .as_lookup()
+                    .records()
+                    .iter()
+                    .filter(|r| r.record_type() == RecordType::CNAME)
+                    .filter_map(|r| match r.data() {
+                        Some(RData::CNAME(target)) => Some(target.to_utf8()),
+                        _ => None,
+                    })
+                    .collect();
+
+                tracing::debug!(
+                    "Resolved {} to {:?} via {} (CNAME chain: {:?})",
+                    host,
+                    resolution.addresses,
+                    resolution.resolver,
+                    resolution.cname_chain
+                );
+                Ok(resolution)
+            }
+            Err(e) => {
+                tracing::debug!("Failed to resolve {} via {}: {}", host, resolution.resolver, e);
+                Err((resolution, ProbeError::new(ProbeStage::Dns, classify(&e), e)))
+            }
+        }
+    }
+}
+
+/// Maps a resolver error onto the DNS failure reasons.
+fn classify(err: &ResolveError) -> FailureReason {
+    match err.kind() {
+        ResolveErrorKind::NoRecordsFound { response_code, .. } => {
+            if *response_code == ResponseCode::NXDomain {
+                FailureReason::DnsNxDomain
+            } else {
+                FailureReason::DnsNoRecords
+            }
+        }
+        ResolveErrorKind::Timeout => FailureReason::DnsTimeout,
+        ResolveErrorKind::Io(e) if e.kind() == std::io::ErrorKind::TimedOut => FailureReason::DnsTimeout,
+        _ => FailureReason::DnsFailure,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn ip_literal_should_resolve_without_a_query() {
+        let resolver = DnsResolver::new(ResolverConfig::new(), ResolverOpts::default()).unwrap();
+
+        let res = resolver.resolve("127.0.0.1").await.unwrap();
+
+        assert_eq!(vec!["127.0.0.1".parse::<IpAddr>().unwrap()], res.addresses);
+        assert!(res.cname_chain.is_empty());
+        assert_eq!("none configured", res.resolver);
+    }
+
+    #[tokio::test]
+    async fn failed_lookup_should_report_dns_stage() {
+        let resolver = DnsResolver::new(ResolverConfig::new(), ResolverOpts::default()).unwrap();
+
+        let (res, err) = resolver.resolve("mcr.microsoft.com").await.unwrap_err();
+
+        assert_eq!(ProbeStage::Dns, err.stage);
+        assert_eq!(FailureReason::DnsFailure, err.reason);
+        assert_eq!("mcr.microsoft.com", res.query);
+        assert!(res.addresses.is_empty());
+    }
+
+    #[test]
+    fn resolver_errors_should_map_to_dns_reasons() {
+        let nxdomain = ResolveError::from(ResolveErrorKind::NoRecordsFound {
+            query: Box::default(),
+            soa: None,
+            negative_ttl: None,
+            response_code: ResponseCode::NXDomain,
+            trusted: true,
+        });
+        let no_records = ResolveError::from(ResolveErrorKind::NoRecordsFound {
+            query: Box::default(),
+            soa: None,
+            negative_ttl: None,
+            response_code: ResponseCode::NoError,
+            trusted: true,
+        });
+
+        assert_eq!(FailureReason::DnsNxDomain, classify(&nxdomain));
+        assert_eq!(FailureReason::DnsNoRecords, classify(&no_records));
+        assert_eq!(FailureReason::DnsTimeout, classify(&ResolveError::from(ResolveErrorKind::Timeout)));
+        assert_eq!(FailureReason::DnsFailure, classify(&ResolveError::from(ResolveErrorKind::NoConnections)));
+    }
+}
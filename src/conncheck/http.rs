@@ -0,0 +1,490 @@
+use std::{
+    io,
+    net::SocketAddr,
+    sync::{Arc, OnceLock},
+    time::Instant,
+};
+
+use anyhow::{anyhow, Result};
+use reqwest::Url;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_rustls::{
+    rustls::{self, ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName},
+    TlsConnector,
+};
+
+use super::{
+    elapsed_ms,
+    proxy::{self, Route},
+    FailureReason, ProbeError, ProbeStage, ProbeTimeouts, ProbeTimings,
+};
+
+/// Upper bound on how much of the response we're willing to buffer while looking for the end of the headers.
+const MAX_RESPONSE_HEAD_LEN: usize = 16 * 1024;
+
+/// Status used by Azure Firewall when an application rule denies a plain HTTP request.
+const AZURE_FIREWALL_DENY_STATUS: u16 = 470;
+
+/// Headers that mark a response as coming from a proxy or firewall rather than the destination itself.
+const MIDDLEBOX_HEADERS: [&str; 3] = ["via", "proxy-agent", "x-squid-error"];
+
+/// Result of an HTTP(S) probe that got a response back from the destination.
+#[derive(Clone, Debug)]
+pub(crate) struct HttpProbeResult {
+    pub status_code: u16,
+    pub peer_addr: SocketAddr,
+    /// Set when the response looks like a firewall or proxy rejecting the request rather than the destination
+    /// answering it.
+    pub rejection: Option<FailureReason>,
+}
+
+/// Status code and headers from an HTTP/1.x response.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct ResponseHead {
+    pub status_code: u16,
+    pub headers: Vec<(String, String)>,
+}
+
+impl ResponseHead {
+    /// Returns the value of the first header matching `name`, ignoring case.
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Performs an application-layer probe against `host:port` using the already resolved `addrs`.
+///
+/// The probe opens a TCP connection, performs a TLS handshake (using the host as the SNI value) when `tls` is set,
+/// and then sends a minimal `GET /` request. Any HTTP response from the destination means it's reachable at the
+/// application layer, so the status code is returned as-is. Responses that look like they came from a firewall or
+/// proxy denying the request are flagged in `rejection`.
+///
+/// When the route goes through a proxy, `addrs` are the proxy's addresses. HTTPS requests are sent through a
+/// `CONNECT` tunnel, while plain HTTP requests are sent to the proxy in absolute form the way any HTTP client would.
+///
+/// Each stage is bounded by the matching value in `timeouts`, and how long each stage took is recorded in `timings`
+/// as it completes (or fails).
+#[tracing::instrument(skip(addrs, route, timings))]
+pub(crate) async fn probe(
+    host: &str,
+    addrs: &[SocketAddr],
+    port: u16,
+    tls: bool,
+    route: &Route<'_>,
+    timeouts: &ProbeTimeouts,
+    timings: &mut ProbeTimings,
+) -> Result<HttpProbeResult, ProbeError> {
+    let connect_start = Instant::now();
+    let opened = proxy::open(route, addrs, host, port, tls, timeouts).await;
+    timings.connect_ms = Some(elapsed_ms(connect_start));
+    let (mut stream, peer_addr) = opened?;
+    tracing::debug!("Connected to {} for {}:{}", peer_addr, host, port);
+
+    let head = if tls {
+        let server_name = ServerName::try_from(host).map_err(|e| {
+            ProbeError::new(
+                ProbeStage::Tls,
+                FailureReason::InvalidRule,
+                anyhow!("{} is not a valid TLS server name: {}", host, e),
+            )
+        })?;
+        let handshake_start = Instant::now();
+        let handshake = TlsConnector::from(route.tls_config.clone()).connect(server_name, stream);
+        let handshake_res = tokio::time::timeout(timeouts.handshake, handshake).await;
+        timings.tls_ms = Some(elapsed_ms(handshake_start));
+        let mut tls_stream = handshake_res
+            .map_err(|_| {
+                ProbeError::new(
+                    ProbeStage::Tls,
+                    FailureReason::TlsHandshakeTimeout,
+                    anyhow!("TLS handshake with {} timed out after {:?}", host, timeouts.handshake),
+                )
+            })?
+            .map_err(|e| tls_error(host, e))?;
+
+        let request = build_request(host, port, tls, None);
+        exchange(&mut tls_stream, host, &request, timeouts, timings).await?
+    } else {
+        let request = build_request(host, port, tls, route.proxy);
+        exchange(&mut stream, host, &request, timeouts, timings).await?
+    };
+
+    // a plain HTTP request through a proxy gets a gateway error from the proxy when it can't reach the destination
+    let rejection = match route.proxy {
+        Some(_) if !tls => classify_response(&head).or_else(|| classify_proxy_response(&head)),
+        _ => classify_response(&head),
+    };
+
+    Ok(HttpProbeResult {
+        status_code: head.status_code,
+        peer_addr,
+        rejection,
+    })
+}
+
+/// Sends the request and waits for the response head, bounded by the response timeout.
+///
+/// Time to first byte is measured from just before the request is written to the first byte of the response.
+async fn exchange<S>(
+    stream: &mut S,
+    host: &str,
+    request: &str,
+    timeouts: &ProbeTimeouts,
+    timings: &mut ProbeTimings,
+) -> Result<ResponseHead, ProbeError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    let request_start = Instant::now();
+    let mut first_byte_at: Option<Instant> = None;
+    let res = tokio::time::timeout(timeouts.response, send_request(stream, request, &mut first_byte_at)).await;
+    timings.first_byte_ms = first_byte_at.map(|t| (t - request_start).as_secs_f64() * 1000.0);
+
+    res
+        .map_err(|_| {
+            ProbeError::new(
+                ProbeStage::Application,
+                FailureReason::ResponseTimeout,
+                anyhow!("no HTTP response from {} within {:?}", host, timeouts.response),
+            )
+        })?
+}
+
+/// Builds a `GET /` request for the destination. Requests sent to a proxy use the absolute URL as the target and
+/// carry the proxy credentials, if the proxy URL has any.
+fn build_request(host: &str, port: u16, tls: bool, proxy: Option<&Url>) -> String {
+    let default_port = if tls { 443 } else { 80 };
+    let host_header = if port == default_port {
+        host.to_string()
+    } else {
+        proxy::authority(host, port)
+    };
+
+    let mut request = match proxy {
+        Some(proxy) => {
+            let mut request = format!("GET http://{}/ HTTP/1.1\r\n", host_header);
+            if let Some(auth) = proxy::proxy_authorization(proxy) {
+                request.push_str(&format!("Proxy-Authorization: {}\r\n", auth));
+            }
+            request
+        }
+        None => String::from("GET / HTTP/1.1\r\n"),
+    };
+    request.push_str(&format!(
+        "Host: {}\r\nUser-Agent: aks-egress-checker/{}\r\nAccept: */*\r\nConnection: close\r\n\r\n",
+        host_header,
+        env!("CARGO_PKG_VERSION")
+    ));
+
+    request
+}
+
+/// Writes the request to the stream and returns the head of the response.
+async fn send_request<S>(
+    stream: &mut S,
+    request: &str,
+    first_byte_at: &mut Option<Instant>,
+) -> Result<ResponseHead, ProbeError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    stream
+        .write_all(request.as_bytes())
+        .await
+        .map_err(|e| ProbeError::io(ProbeStage::Application, e))?;
+    stream
+        .flush()
+        .await
+        .map_err(|e| ProbeError::io(ProbeStage::Application, e))?;
+
+    read_response_head(stream, first_byte_at).await
+}
+
+/// Reads from the stream until the blank line that ends the response headers and parses what was read.
+///
+/// `first_byte_at` is set when the first byte of the response arrives, if it hasn't been set already.
+pub(crate) async fn read_response_head<S>(
+    stream: &mut S,
+    first_byte_at: &mut Option<Instant>,
+) -> Result<ResponseHead, ProbeError>
+where
+    S: AsyncRead + Unpin + ?Sized,
+{
+    let mut buf: Vec<u8> = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 1024];
+
+    loop {
+        if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            return parse_response_head(&String::from_utf8_lossy(&buf[..end]))
+                .map_err(|e| ProbeError::new(ProbeStage::Application, FailureReason::InvalidResponse, e));
+        }
+
+        if buf.len() > MAX_RESPONSE_HEAD_LEN {
+            return Err(ProbeError::new(
+                ProbeStage::Application,
+                FailureReason::InvalidResponse,
+                anyhow!("response headers were larger than {} bytes", MAX_RESPONSE_HEAD_LEN),
+            ));
+        }
+
+        let n = stream
+            .read(&mut chunk)
+            .await
+            .map_err(|e| ProbeError::io(ProbeStage::Application, e))?;
+        if n == 0 {
+            return Err(ProbeError::new(
+                ProbeStage::Application,
+                FailureReason::ConnectionReset,
+                anyhow!("connection was closed before a response was received"),
+            ));
+        }
+        first_byte_at.get_or_insert_with(Instant::now);
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
+
+/// Parses the status line and headers of a response, without the trailing blank line.
+fn parse_response_head(raw: &str) -> Result<ResponseHead> {
+    let mut lines = raw.split("\r\n");
+    let status_code = parse_status_line(lines.next().unwrap_or_default())?;
+    let headers = lines
+        .filter_map(|l| l.split_once(':'))
+        .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+        .collect();
+
+    Ok(ResponseHead { status_code, headers })
+}
+
+/// Pulls the status code out of an HTTP/1.x status line, e.g. `HTTP/1.1 404 Not Found`.
+pub(crate) fn parse_status_line(line: &str) -> Result<u16> {
+    let mut parts = line.split_whitespace();
+
+    match (parts.next(), parts.next()) {
+        (Some(version), Some(code)) if version.starts_with("HTTP/") => code
+            .parse::<u16>()
+            .map_err(|_| anyhow!("invalid status code in response: {:?}", line)),
+        _ => Err(anyhow!("malformed HTTP status line: {:?}", line)),
+    }
+}
+
+/// Decides whether a response came from something in the path denying the request.
+///
+/// Destinations answer `GET /` with all sorts of codes (MCR returns 404, ARM returns 400), so only codes that are
+/// specific to proxies and firewalls count as a denial, plus a 403 when the response carries a proxy header.
+pub(crate) fn classify_response(head: &ResponseHead) -> Option<FailureReason> {
+    match head.status_code {
+        407 => Some(FailureReason::ProxyRejected),
+        AZURE_FIREWALL_DENY_STATUS | 451 => Some(FailureReason::HttpDenyStatus),
+        403 if MIDDLEBOX_HEADERS.iter().any(|h| head.header(h).is_some()) => Some(FailureReason::HttpDenyStatus),
+        _ => None,
+    }
+}
+
+/// Flags gateway errors sent back by a proxy that couldn't reach the destination for a plain HTTP request.
+fn classify_proxy_response(head: &ResponseHead) -> Option<FailureReason> {
+    match head.status_code {
+        502..=504 => Some(FailureReason::ProxyRejected),
+        _ => None,
+    }
+}
+
+/// Classifies a failed TLS handshake, separating certificate trust problems from resets and other failures.
+pub(crate) fn tls_error(host: &str, err: io::Error) -> ProbeError {
+    let reason = match err.get_ref().and_then(|inner| inner.downcast_ref::<rustls::Error>()) {
+        Some(rustls::Error::InvalidCertificate(_)) => FailureReason::CertificateUntrusted,
+        Some(_) => FailureReason::TlsHandshakeFailure,
+        None => match FailureReason::from_io_kind(ProbeStage::Tls, err.kind()) {
+            FailureReason::Other => FailureReason::TlsHandshakeFailure,
+            reason => reason,
+        },
+    };
+
+    ProbeError::new(ProbeStage::Tls, reason, anyhow!("TLS handshake with {} failed: {}", host, err))
+}
+
+/// TLS client configuration using the Mozilla root store (the same roots reqwest uses) plus any extra trusted CAs,
+/// such as the `trustedCa` of a TLS-inspecting proxy.
+///
+/// The configuration without extra roots is built once and shared. Certificates that can't be parsed are logged and
+/// skipped.
+pub(crate) fn tls_config(extra_roots: &[Vec<u8>]) -> Arc<ClientConfig> {
+    static CONFIG: OnceLock<Arc<ClientConfig>> = OnceLock::new();
+
+    if extra_roots.is_empty() {
+        return CONFIG.get_or_init(|| build_tls_config(&[])).clone();
+    }
+
+    build_tls_config(extra_roots)
+}
+
+fn build_tls_config(extra_roots: &[Vec<u8>]) -> Arc<ClientConfig> {
+    let mut roots = RootCertStore::empty();
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(ta.subject, ta.spki, ta.name_constraints)
+    }));
+    let (_, ignored) = roots.add_parsable_certificates(extra_roots);
+    if ignored > 0 {
+        log::warn!("Ignored {} trusted CA certificates that could not be parsed", ignored);
+    }
+
+    let mut config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+    Arc::new(config)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    fn direct() -> Route<'static> {
+        Route {
+            proxy: None,
+            tls_config: tls_config(&[]),
+        }
+    }
+
+    #[test]
+    fn status_line_should_parse_code() {
+        assert_eq!(200, parse_status_line("HTTP/1.1 200 OK").unwrap());
+        assert_eq!(404, parse_status_line("HTTP/1.0 404 Not Found").unwrap());
+        assert_eq!(403, parse_status_line("HTTP/1.1 403").unwrap());
+    }
+
+    #[test]
+    fn status_line_should_reject_garbage() {
+        assert!(parse_status_line("").is_err());
+        assert!(parse_status_line("SSH-2.0-OpenSSH_8.9").is_err());
+        assert!(parse_status_line("HTTP/1.1 abc Nope").is_err());
+    }
+
+    #[tokio::test]
+    async fn probe_should_return_status_from_plain_http_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            let (mut sock, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = sock.read(&mut buf).await.unwrap();
+            sock.write_all(b"HTTP/1.1 418 I'm a teapot\r\nContent-Length: 0\r\n\r\n")
+                .await
+                .unwrap();
+        });
+
+        let addrs = [SocketAddr::from(([127, 0, 0, 1], port))];
+        let mut timings = ProbeTimings::default();
+        let res = probe("127.0.0.1", &addrs, port, false, &direct(), &ProbeTimeouts::default(), &mut timings)
+            .await
+            .unwrap();
+
+        assert_eq!(418, res.status_code);
+        assert_eq!(port, res.peer_addr.port());
+        assert_eq!(None, res.rejection);
+        assert!(timings.connect_ms.is_some());
+        assert!(timings.first_byte_ms.is_some());
+        assert_eq!(None, timings.tls_ms);
+    }
+
+    #[tokio::test]
+    async fn silent_server_should_be_reported_as_response_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            // accept the connection and then never answer
+            let (_sock, _) = listener.accept().await.unwrap();
+            tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+        });
+
+        let timeouts = ProbeTimeouts {
+            response: std::time::Duration::from_millis(100),
+            ..ProbeTimeouts::default()
+        };
+        let addrs = [SocketAddr::from(([127, 0, 0, 1], port))];
+        let mut timings = ProbeTimings::default();
+        let err = probe("127.0.0.1", &addrs, port, false, &direct(), &timeouts, &mut timings)
+            .await
+            .unwrap_err();
+
+        assert_eq!(ProbeStage::Application, err.stage);
+        assert_eq!(FailureReason::ResponseTimeout, err.reason);
+        assert!(timings.connect_ms.is_some());
+        assert_eq!(None, timings.first_byte_ms);
+    }
+
+    #[tokio::test]
+    async fn plain_http_through_proxy_should_report_gateway_errors() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+
+        let handle = tokio::spawn(async move {
+            let (mut sock, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 1024];
+            let n = sock.read(&mut buf).await.unwrap();
+            sock.write_all(b"HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\n\r\n")
+                .await
+                .unwrap();
+            String::from_utf8_lossy(&buf[..n]).into_owned()
+        });
+
+        let proxy_url = proxy::parse_proxy_url(&format!("http://user:pass@{}", proxy_addr)).unwrap();
+        let route = Route {
+            proxy: Some(&proxy_url),
+            tls_config: tls_config(&[]),
+        };
+        let res = probe(
+            "packages.microsoft.com",
+            &[proxy_addr],
+            80,
+            false,
+            &route,
+            &ProbeTimeouts::default(),
+            &mut ProbeTimings::default(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(502, res.status_code);
+        assert_eq!(proxy_addr, res.peer_addr);
+        assert_eq!(Some(FailureReason::ProxyRejected), res.rejection);
+
+        let request = handle.await.unwrap();
+        assert!(request.starts_with("GET http://packages.microsoft.com/ HTTP/1.1\r\n"));
+        assert!(request.contains("Proxy-Authorization: Basic dXNlcjpwYXNz\r\n"));
+    }
+
+    #[test]
+    fn response_head_should_parse_headers() {
+        let head = parse_response_head("HTTP/1.1 403 Forbidden\r\nVia: 1.1 squid\r\nContent-Length: 0").unwrap();
+
+        assert_eq!(403, head.status_code);
+        assert_eq!(Some("1.1 squid"), head.header("via"));
+        assert_eq!(None, head.header("server"));
+    }
+
+    #[test]
+    fn firewall_and_proxy_responses_should_be_rejections() {
+        let head = |status_code: u16, headers: &[(&str, &str)]| ResponseHead {
+            status_code,
+            headers: headers.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+        };
+
+        assert_eq!(Some(FailureReason::HttpDenyStatus), classify_response(&head(470, &[])));
+        assert_eq!(Some(FailureReason::ProxyRejected), classify_response(&head(407, &[])));
+        assert_eq!(
+            Some(FailureReason::HttpDenyStatus),
+            classify_response(&head(403, &[("X-Squid-Error", "ERR_ACCESS_DENIED 0")]))
+        );
+        // a bare 403 (or a 404 from MCR) is the destination answering, which is what we want to know
+        assert_eq!(None, classify_response(&head(403, &[("Server", "nginx")])));
+        assert_eq!(None, classify_response(&head(404, &[])));
+    }
+}
@@ -0,0 +1,94 @@
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+use tokio::{io::AsyncWriteExt, net::TcpStream};
+
+use super::{elapsed_ms, FailureReason, ProbeError, ProbeStage, ProbeTimings};
+
+/// Attempts a connection to each resolved address in turn, returning the first one that succeeds.
+///
+/// Each attempt gets up to `timeout` before moving on to the next address. If every address fails, the error from
+/// the last attempt is returned.
+pub(crate) async fn connect_any(addrs: &[SocketAddr], timeout: Duration) -> Result<TcpStream, ProbeError> {
+    let mut last_err = None;
+
+    for addr in addrs {
+        match tokio::time::timeout(timeout, TcpStream::connect(addr)).await {
+            Ok(Ok(stream)) => return Ok(stream),
+            Ok(Err(e)) => {
+                tracing::debug!("Connection to {} failed: {}", addr, e);
+                last_err = Some(ProbeError::io(ProbeStage::Connect, e));
+            }
+            Err(_) => {
+                tracing::debug!("Connection to {} timed out after {:?}", addr, timeout);
+                last_err = Some(ProbeError::new(
+                    ProbeStage::Connect,
+                    FailureReason::ConnectTimeout,
+                    anyhow!("connection to {} timed out after {:?}", addr, timeout),
+                ));
+            }
+        }
+    }
+
+    Err(last_err.unwrap_or_else(|| {
+        ProbeError::new(
+            ProbeStage::Connect,
+            FailureReason::Other,
+            anyhow!("no addresses were available to connect to"),
+        )
+    }))
+}
+
+/// Opens (and then closes) a TCP connection to the first reachable address, returning the peer that accepted it.
+#[tracing::instrument(skip(timings))]
+pub(crate) async fn probe(
+    addrs: &[SocketAddr],
+    timeout: Duration,
+    timings: &mut ProbeTimings,
+) -> Result<SocketAddr, ProbeError> {
+    let connect_start = Instant::now();
+    let connected = connect_any(addrs, timeout).await;
+    timings.connect_ms = Some(elapsed_ms(connect_start));
+    let mut stream = connected?;
+    let peer_addr = stream
+        .peer_addr()
+        .map_err(|e| ProbeError::io(ProbeStage::Connect, e))?;
+
+    let _ = stream.shutdown().await;
+
+    Ok(peer_addr)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn closed_port_should_be_reported_as_refused() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        let err = probe(&[addr], Duration::from_secs(5), &mut ProbeTimings::default())
+            .await
+            .unwrap_err();
+
+        assert_eq!(ProbeStage::Connect, err.stage);
+        assert_eq!(FailureReason::ConnectionRefused, err.reason);
+    }
+
+    #[tokio::test]
+    async fn listening_port_should_pass() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut timings = ProbeTimings::default();
+
+        assert_eq!(addr, probe(&[addr], Duration::from_secs(5), &mut timings).await.unwrap());
+        assert!(timings.connect_ms.is_some());
+    }
+}
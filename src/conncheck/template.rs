@@ -0,0 +1,203 @@
+use std::{collections::BTreeMap, fmt, fs, path::Path};
+
+use anyhow::{anyhow, Context, Result};
+
+/// Variables the shipped egress data uses in rule destinations, in their normalized spelling.
+pub const KNOWN_VARIABLES: [&str; 8] =
+    ["region", "location", "ccp-id", "ccp-fqdn", "endpoint", "id", "spec-url", "dns-server"];
+
+/// Values for the `{name}` placeholders in rule destinations.
+///
+/// Names are normalized when they're set and looked up, so `{ccp_id}`, `{ccp-id}` and `{CCP_ID}` all refer to the
+/// same variable.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TemplateVars {
+    vars: BTreeMap<String, String>,
+}
+
+/// Placeholders that had no value when a template was rendered, in the order they appear.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UnresolvedVariables(pub Vec<String>);
+
+impl fmt::Display for UnresolvedVariables {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<String> = self.0.iter().map(|n| format!("{{{}}}", n)).collect();
+        write!(f, "unresolved variable {}", names.join(", "))
+    }
+}
+
+impl TemplateVars {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set(&mut self, name: &str, value: impl Into<String>) {
+        self.vars.insert(normalize_name(name), value.into());
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.vars.get(&normalize_name(name)).map(|v| v.as_str())
+    }
+
+    /// Copies every variable from `other`, replacing any values already set here.
+    pub fn extend(&mut self, other: &TemplateVars) {
+        self.vars.extend(other.vars.iter().map(|(k, v)| (k.clone(), v.clone())));
+    }
+
+    /// Loads variables from a file, either a JSON object of string values (for `.json` files) or `name=value` lines
+    /// where blank lines and lines starting with `#` are ignored.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let raw = fs::read_to_string(path).with_context(|| format!("failed to read vars file {}", path.display()))?;
+        let mut vars = TemplateVars::new();
+
+        if path.extension().map(|e| e == "json").unwrap_or(false) {
+            let parsed: BTreeMap<String, String> = serde_json::from_str(&raw)
+                .with_context(|| format!("{} is not a JSON object of string values", path.display()))?;
+            parsed.iter().for_each(|(k, v)| vars.set(k, v.as_str()));
+        } else {
+            for (idx, line) in raw.lines().enumerate() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+
+                let (name, value) =
+                    parse_assignment(line).map_err(|e| anyhow!("{}:{}: {}", path.display(), idx + 1, e))?;
+                vars.set(&name, value);
+            }
+        }
+
+        Ok(vars)
+    }
+
+    /// Replaces every `{name}` placeholder in `template` with its value.
+    ///
+    /// If any placeholder has no value, all of the missing names are returned instead so the caller can report them
+    /// together rather than probing a destination with a literal `{id}` in it.
+    pub fn render(&self, template: &str) -> Result<String, UnresolvedVariables> {
+        let mut rendered = String::with_capacity(template.len());
+        let mut missing: Vec<String> = Vec::new();
+        let mut rest = template;
+
+        while let Some((before, name, after)) = next_placeholder(rest) {
+            rendered.push_str(before);
+            match self.get(name) {
+                Some(value) => rendered.push_str(value),
+                None => {
+                    let name = normalize_name(name);
+                    if !missing.contains(&name) {
+                        missing.push(name);
+                    }
+                }
+            }
+            rest = after;
+        }
+        rendered.push_str(rest);
+
+        if missing.is_empty() {
+            Ok(rendered)
+        } else {
+            Err(UnresolvedVariables(missing))
+        }
+    }
+}
+
+/// Lists the (normalized) names of the placeholders used in a template.
+pub fn variables(template: &str) -> Vec<String> {
+    let mut names = Vec::new();
+    let mut rest = template;
+
+    while let Some((_, name, after)) = next_placeholder(rest) {
+        names.push(normalize_name(name));
+        rest = after;
+    }
+
+    names
+}
+
+/// Parses a `name=value` assignment, as given to `--var`.
+pub fn parse_assignment(val: &str) -> Result<(String, String)> {
+    match val.split_once('=') {
+        Some((name, value)) if is_valid_name(name.trim()) => Ok((normalize_name(name.trim()), value.trim().to_string())),
+        _ => Err(anyhow!("'{}' is not a name=value pair", val)),
+    }
+}
+
+/// Lowercases a variable name and treats `_` and `-` as the same character.
+pub fn normalize_name(name: &str) -> String {
+    name.trim().to_ascii_lowercase().replace('_', "-")
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Finds the next `{name}` placeholder, returning the text before it, the name, and the text after it. Braces that
+/// don't wrap a valid name are left alone.
+fn next_placeholder(template: &str) -> Option<(&str, &str, &str)> {
+    let mut search_from = 0;
+
+    while let Some(open) = template[search_from..].find('{').map(|i| i + search_from) {
+        let close = template[open..].find('}').map(|i| i + open)?;
+        let name = &template[open + 1..close];
+
+        if is_valid_name(name) {
+            return Some((&template[..open], name, &template[close + 1..]));
+        }
+        search_from = open + 1;
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn render_should_normalize_names() {
+        let mut vars = TemplateVars::new();
+        vars.set("ccp-id", "myaks-abc123");
+        vars.set("REGION", "eastus2");
+
+        assert_eq!(
+            "myaks-abc123.hcp.eastus2.azmk8s.io",
+            vars.render("{ccp_id}.hcp.{region}.azmk8s.io").unwrap()
+        );
+        assert_eq!("myaks-abc123.tun.eastus2", vars.render("{CCP-ID}.tun.{Region}").unwrap());
+        assert_eq!("mcr.microsoft.com", vars.render("mcr.microsoft.com").unwrap());
+    }
+
+    #[test]
+    fn render_should_report_every_missing_variable() {
+        let mut vars = TemplateVars::new();
+        vars.set("region", "eastus2");
+
+        let err = vars.render("{id}.ods.{region}.{endpoint}.{id}").unwrap_err();
+
+        assert_eq!(vec!["id", "endpoint"], err.0);
+        assert_eq!("unresolved variable {id}, {endpoint}", err.to_string());
+    }
+
+    #[test]
+    fn variables_should_skip_braces_without_a_name() {
+        assert_eq!(vec!["ccp-id", "location"], variables("{ccp_id}.hcp.{location}.{}.{not valid}"));
+    }
+
+    #[test]
+    fn assignments_should_parse() {
+        assert_eq!(
+            ("id".to_string(), "0000-1111".to_string()),
+            parse_assignment("id=0000-1111").unwrap()
+        );
+        assert_eq!(
+            ("spec-url".to_string(), "a=b".to_string()),
+            parse_assignment("spec_url=a=b").unwrap()
+        );
+        assert!(parse_assignment("no-value").is_err());
+        assert!(parse_assignment("=value").is_err());
+    }
+}
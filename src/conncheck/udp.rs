@@ -0,0 +1,278 @@
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use tokio::net::UdpSocket;
+
+use super::{elapsed_ms, FailureReason, ProbeError, ProbeStage, ProbeTimings};
+
+/// Well-known port for NTP, used to decide when a UDP rule gets an application-layer probe.
+pub(crate) const NTP_PORT: u16 = 123;
+
+/// Seconds between the NTP era 0 epoch (1900-01-01) and the Unix epoch (1970-01-01).
+const NTP_UNIX_OFFSET_SECS: u64 = 2_208_988_800;
+
+const NTP_PACKET_LEN: usize = 48;
+
+/// Details reported by an NTP server that answered our client request.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct NtpDetails {
+    /// Stratum of the responding server, 1 (primary reference) through 15.
+    pub stratum: u8,
+    /// Estimated offset of the local clock relative to the server, in milliseconds.
+    pub offset_ms: f64,
+    /// Round-trip delay of the exchange excluding server processing time, in milliseconds.
+    pub delay_ms: f64,
+    /// Address of the server that answered.
+    pub server: SocketAddr,
+}
+
+/// Sends an NTP (v4, client mode) request to each resolved address in turn until one of them sends back a valid
+/// reply, waiting up to `timeout` for each.
+///
+/// Unlike a bare `UdpSocket::connect`, this puts a packet on the wire, so a pass means the request made it out
+/// and a reply made it back. The time from sending the request to receiving the reply is recorded as the time to
+/// first byte in `timings`. If no address answers, the error from the last attempt is returned.
+#[tracing::instrument(skip(timings))]
+pub(crate) async fn probe_ntp(
+    addrs: &[SocketAddr],
+    timeout: Duration,
+    timings: &mut ProbeTimings,
+) -> Result<NtpDetails, ProbeError> {
+    let mut last_err = None;
+
+    for addr in addrs {
+        match exchange_ntp(*addr, timeout, timings).await {
+            Ok(details) => return Ok(details),
+            Err(e) => {
+                tracing::debug!("NTP request to {} failed: {}", addr, e);
+                last_err = Some(e);
+            }
+        }
+    }
+
+    Err(last_err.unwrap_or_else(no_addresses))
+}
+
+/// Sends a single NTP request to one address and waits up to `timeout` for a valid reply.
+async fn exchange_ntp(addr: SocketAddr, timeout: Duration, timings: &mut ProbeTimings) -> Result<NtpDetails, ProbeError> {
+    let sock = connect(addr).await?;
+
+    let request_start = Instant::now();
+    let sent_at = SystemTime::now();
+    let request = build_request(to_ntp_timestamp(sent_at));
+    sock.send(&request)
+        .await
+        .map_err(|e| ProbeError::io(ProbeStage::Connect, e))?;
+
+    let mut buf = [0u8; 1024];
+    let received = tokio::time::timeout(timeout, sock.recv(&mut buf)).await;
+    let len = received
+        .map_err(|_| {
+            ProbeError::new(
+                ProbeStage::Application,
+                FailureReason::ResponseTimeout,
+                anyhow!("no NTP response from {} within {:?}", addr, timeout),
+            )
+        })?
+        .map_err(|e| ProbeError::io(ProbeStage::Application, e))?;
+    let received_at = SystemTime::now();
+    timings.first_byte_ms = Some(elapsed_ms(request_start));
+
+    parse_response(&buf[..len], &request, sent_at, received_at, addr)
+        .map_err(|e| ProbeError::new(ProbeStage::Application, FailureReason::InvalidResponse, e))
+}
+
+/// Connects a UDP socket to each resolved address in turn without sending anything, returning the first one the
+/// node has a route to.
+///
+/// This only confirms that the node has a route to the destination, it says nothing about whether packets are
+/// actually allowed through, so callers mustn't treat it as a pass.
+#[tracing::instrument]
+pub(crate) async fn probe_route(addrs: &[SocketAddr]) -> Result<SocketAddr, ProbeError> {
+    let mut last_err = None;
+
+    for addr in addrs {
+        match connect(*addr).await {
+            Ok(_) => return Ok(*addr),
+            Err(e) => {
+                tracing::debug!("No route to {}: {}", addr, e);
+                last_err = Some(e);
+            }
+        }
+    }
+
+    Err(last_err.unwrap_or_else(no_addresses))
+}
+
+async fn connect(addr: SocketAddr) -> Result<UdpSocket, ProbeError> {
+    let bind_addr = if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+    let sock = UdpSocket::bind(bind_addr)
+        .await
+        .map_err(|e| ProbeError::io(ProbeStage::Connect, e))?;
+    sock.connect(addr)
+        .await
+        .map_err(|e| ProbeError::io(ProbeStage::Connect, e))?;
+
+    Ok(sock)
+}
+
+fn no_addresses() -> ProbeError {
+    ProbeError::new(
+        ProbeStage::Connect,
+        FailureReason::Other,
+        anyhow!("no addresses were available to connect to"),
+    )
+}
+
+/// Builds a 48 byte client request with LI = 0, VN = 4, Mode = 3 and our transmit timestamp set.
+fn build_request(transmit_ts: u64) -> [u8; NTP_PACKET_LEN] {
+    let mut packet = [0u8; NTP_PACKET_LEN];
+    packet[0] = (4 << 3) | 3;
+    packet[40..48].copy_from_slice(&transmit_ts.to_be_bytes());
+
+    packet
+}
+
+/// Validates a server reply against the request that was sent and calculates the clock offset and delay.
+fn parse_response(
+    buf: &[u8],
+    request: &[u8; NTP_PACKET_LEN],
+    sent_at: SystemTime,
+    received_at: SystemTime,
+    server: SocketAddr,
+) -> Result<NtpDetails> {
+    if buf.len() < NTP_PACKET_LEN {
+        return Err(anyhow!("NTP response from {} was too short ({} bytes)", server, buf.len()));
+    }
+
+    let mode = buf[0] & 0x7;
+    if mode != 4 {
+        return Err(anyhow!("NTP response from {} was not in server mode (mode {})", server, mode));
+    }
+
+    // the origin timestamp must echo our transmit timestamp, otherwise this isn't a reply to our request
+    if buf[24..32] != request[40..48] {
+        return Err(anyhow!("NTP response from {} did not match the request that was sent", server));
+    }
+
+    let stratum = buf[1];
+    if stratum == 0 {
+        let kiss_code = String::from_utf8_lossy(&buf[12..16]).into_owned();
+        return Err(anyhow!("NTP server {} sent a kiss-o'-death packet ({})", server, kiss_code));
+    }
+    if stratum > 15 {
+        return Err(anyhow!("NTP server {} reported itself as unsynchronized (stratum {})", server, stratum));
+    }
+
+    let t1 = ntp_secs(to_ntp_timestamp(sent_at));
+    let t2 = ntp_secs(read_timestamp(&buf[32..40]));
+    let t3 = ntp_secs(read_timestamp(&buf[40..48]));
+    let t4 = ntp_secs(to_ntp_timestamp(received_at));
+
+    Ok(NtpDetails {
+        stratum,
+        offset_ms: ((t2 - t1) + (t3 - t4)) / 2.0 * 1000.0,
+        delay_ms: ((t4 - t1) - (t3 - t2)) * 1000.0,
+        server,
+    })
+}
+
+fn read_timestamp(bytes: &[u8]) -> u64 {
+    let mut raw = [0u8; 8];
+    raw.copy_from_slice(bytes);
+
+    u64::from_be_bytes(raw)
+}
+
+/// Converts a system time into a 64-bit NTP timestamp (32 bits of seconds, 32 bits of fraction).
+fn to_ntp_timestamp(t: SystemTime) -> u64 {
+    let since_unix = t.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_unix.as_secs() + NTP_UNIX_OFFSET_SECS;
+    let frac = ((since_unix.subsec_nanos() as u64) << 32) / 1_000_000_000;
+
+    (secs << 32) | frac
+}
+
+fn ntp_secs(ts: u64) -> f64 {
+    (ts >> 32) as f64 + (ts & 0xFFFF_FFFF) as f64 / 4_294_967_296.0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn server_reply(request: &[u8], stratum: u8, server_ts: u64) -> [u8; NTP_PACKET_LEN] {
+        let mut reply = [0u8; NTP_PACKET_LEN];
+        reply[0] = (4 << 3) | 4;
+        reply[1] = stratum;
+        reply[24..32].copy_from_slice(&request[40..48]);
+        reply[32..40].copy_from_slice(&server_ts.to_be_bytes());
+        reply[40..48].copy_from_slice(&server_ts.to_be_bytes());
+
+        reply
+    }
+
+    #[test]
+    fn response_should_report_stratum_and_offset() {
+        let sent_at = SystemTime::now();
+        let received_at = sent_at + Duration::from_millis(20);
+        let request = build_request(to_ntp_timestamp(sent_at));
+        // server clock is 500ms ahead of the midpoint of the exchange
+        let server_ts = to_ntp_timestamp(sent_at + Duration::from_millis(510));
+        let reply = server_reply(&request, 2, server_ts);
+
+        let details = parse_response(&reply, &request, sent_at, received_at, "127.0.0.1:123".parse().unwrap()).unwrap();
+
+        assert_eq!(2, details.stratum);
+        assert!((details.offset_ms - 500.0).abs() < 1.0, "offset was {}", details.offset_ms);
+        assert!((details.delay_ms - 20.0).abs() < 1.0, "delay was {}", details.delay_ms);
+    }
+
+    #[test]
+    fn response_should_be_rejected_when_invalid() {
+        let sent_at = SystemTime::now();
+        let request = build_request(to_ntp_timestamp(sent_at));
+        let server: SocketAddr = "127.0.0.1:123".parse().unwrap();
+
+        let kod = server_reply(&request, 0, to_ntp_timestamp(sent_at));
+        assert!(parse_response(&kod, &request, sent_at, sent_at, server).is_err());
+
+        let mut wrong_origin = server_reply(&request, 2, to_ntp_timestamp(sent_at));
+        wrong_origin[24] ^= 0xFF;
+        assert!(parse_response(&wrong_origin, &request, sent_at, sent_at, server).is_err());
+
+        assert!(parse_response(&request, &request, sent_at, sent_at, server).is_err());
+    }
+
+    #[tokio::test]
+    async fn probe_should_try_each_address_until_one_answers() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = server.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            let mut buf = [0u8; NTP_PACKET_LEN];
+            let (_, peer) = server.recv_from(&mut buf).await.unwrap();
+            let reply = server_reply(&buf, 3, to_ntp_timestamp(SystemTime::now()));
+            server.send_to(&reply, peer).await.unwrap();
+        });
+
+        // nothing listens on the first address, so the probe has to move on to the second
+        let dead = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let dead_addr = dead.local_addr().unwrap();
+        drop(dead);
+
+        let mut timings = ProbeTimings::default();
+        let addr = SocketAddr::from(([127, 0, 0, 1], port));
+        let details = probe_ntp(&[dead_addr, addr], Duration::from_millis(500), &mut timings)
+            .await
+            .unwrap();
+
+        assert_eq!(3, details.stratum);
+        assert_eq!(addr, details.server);
+        assert!(timings.first_byte_ms.is_some());
+    }
+}
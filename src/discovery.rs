@@ -0,0 +1,327 @@
+use std::{
+    env, fs,
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+use log::{debug, info, warn};
+use reqwest::Url;
+use serde::Deserialize;
+
+use crate::imds::{self, InstanceMetadata, RetryPolicy};
+
+/// Kubeconfig the kubelet uses on AKS nodes.
+pub const KUBELET_KUBECONFIG: &str = "/var/lib/kubelet/kubeconfig";
+/// Kubeconfig the kubelet uses for TLS bootstrapping, before its own kubeconfig has been written.
+pub const BOOTSTRAP_KUBECONFIG: &str = "/var/lib/kubelet/bootstrap-kubeconfig";
+
+/// Tags AKS sets on node VMs that name the managed cluster they belong to.
+const CLUSTER_NAME_TAG: &str = "aks-managed-cluster-name";
+const CLUSTER_RG_TAG: &str = "aks-managed-cluster-rg";
+
+const MANAGED_CLUSTERS_API_VERSION: &str = "2023-08-01";
+
+/// Where to look for the API server FQDN of the cluster, tried in the order the fields are listed.
+#[derive(Clone, Debug, Default)]
+pub struct FqdnSources {
+    /// `KUBERNETES_SERVICE_HOST`, which AKS points at the API server FQDN in pods.
+    pub service_host: Option<String>,
+    /// Kubeconfigs to read the server of the current context from.
+    pub kubeconfigs: Vec<PathBuf>,
+    /// Looks the cluster up in Azure Resource Manager if none of the other sources have the FQDN.
+    pub arm: Option<ArmLookup>,
+}
+
+/// How to look up the managed cluster in Azure Resource Manager.
+#[derive(Clone, Debug)]
+pub struct ArmLookup {
+    /// Base URL requests are sent to, e.g. `https://management.azure.com/`.
+    pub endpoint: Url,
+    /// Resource the managed identity token is requested for, usually the same as the endpoint.
+    pub resource: String,
+    pub imds_host: String,
+    /// Client ID of the user-assigned identity to use, such as the kubelet identity.
+    pub client_id: Option<String>,
+    /// Resource ID of the managed cluster. Taken from the tags AKS sets on the node when not given.
+    pub cluster_resource_id: Option<String>,
+    pub retry_policy: RetryPolicy,
+}
+
+impl FqdnSources {
+    /// The sources available on an AKS node or in a pod: `KUBERNETES_SERVICE_HOST`, then `$KUBECONFIG`,
+    /// `~/.kube/config`, the kubelet kubeconfig and the bootstrap kubeconfig.
+    pub fn from_env() -> Self {
+        let mut kubeconfigs: Vec<PathBuf> = env::var_os("KUBECONFIG")
+            .map(|paths| env::split_paths(&paths).collect())
+            .unwrap_or_default();
+        if let Some(home) = env::var_os("HOME") {
+            kubeconfigs.push(Path::new(&home).join(".kube").join("config"));
+        }
+        kubeconfigs.push(PathBuf::from(KUBELET_KUBECONFIG));
+        kubeconfigs.push(PathBuf::from(BOOTSTRAP_KUBECONFIG));
+
+        FqdnSources {
+            service_host: env::var("KUBERNETES_SERVICE_HOST").ok(),
+            kubeconfigs,
+            arm: None,
+        }
+    }
+}
+
+/// Finds the API server FQDN of the cluster the node belongs to.
+///
+/// IP addresses are passed over, since the FQDN is what the rules for the control plane are built from. Sources that
+/// don't exist or can't be read are logged and skipped; an error is only returned once every source has been tried.
+#[tracing::instrument(skip(sources, metadata))]
+pub async fn discover_ccp_fqdn(sources: &FqdnSources, metadata: &InstanceMetadata) -> Result<String> {
+    if let Some(host) = sources.service_host.as_deref().filter(|h| is_fqdn(h)) {
+        info!("Using the API server FQDN {} from KUBERNETES_SERVICE_HOST", host);
+        return Ok(host.to_string());
+    }
+
+    for path in sources.kubeconfigs.iter().filter(|p| p.exists()) {
+        match server_from_kubeconfig(path) {
+            Ok(host) if is_fqdn(&host) => {
+                info!("Using the API server FQDN {} from {}", host, path.display());
+                return Ok(host);
+            }
+            Ok(host) => debug!("Skipping {}, its server {} isn't an FQDN", path.display(), host),
+            Err(e) => warn!("Failed to read the API server from {}: {:#}", path.display(), e),
+        }
+    }
+
+    match &sources.arm {
+        Some(arm) => {
+            let fqdn = fqdn_from_arm(arm, metadata).await?;
+            info!("Using the API server FQDN {} from Azure Resource Manager", fqdn);
+            Ok(fqdn)
+        }
+        None => Err(anyhow!(
+            "couldn't find the API server FQDN in KUBERNETES_SERVICE_HOST or a kubeconfig, use --ccp-fqdn to give it or --arm-lookup to look it up in Azure"
+        )),
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+struct Kubeconfig {
+    current_context: String,
+    clusters: Vec<NamedCluster>,
+    contexts: Vec<NamedContext>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct NamedCluster {
+    name: String,
+    cluster: ClusterEntry,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct ClusterEntry {
+    server: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct NamedContext {
+    name: String,
+    context: ContextEntry,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct ContextEntry {
+    cluster: String,
+}
+
+/// Reads the host of the API server for the current context of a kubeconfig, or of its first cluster if there's no
+/// current context (as with the bootstrap kubeconfig).
+fn server_from_kubeconfig(path: &Path) -> Result<String> {
+    let raw = fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))?;
+    let kubeconfig: Kubeconfig =
+        serde_yaml::from_str(&raw).with_context(|| format!("{} is not a valid kubeconfig", path.display()))?;
+
+    let current_cluster = kubeconfig
+        .contexts
+        .iter()
+        .find(|c| c.name == kubeconfig.current_context)
+        .and_then(|c| kubeconfig.clusters.iter().find(|cl| cl.name == c.context.cluster));
+    let cluster = current_cluster
+        .or_else(|| kubeconfig.clusters.first())
+        .ok_or_else(|| anyhow!("{} has no clusters", path.display()))?;
+
+    let server = Url::parse(&cluster.cluster.server)
+        .with_context(|| format!("'{}' is not a valid server URL", cluster.cluster.server))?;
+
+    server
+        .host_str()
+        .map(|h| h.trim_start_matches('[').trim_end_matches(']').to_string())
+        .ok_or_else(|| anyhow!("'{}' has no host", cluster.cluster.server))
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct ManagedCluster {
+    properties: ManagedClusterProperties,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct ManagedClusterProperties {
+    fqdn: Option<String>,
+    #[serde(rename = "privateFQDN")]
+    private_fqdn: Option<String>,
+}
+
+/// Gets the managed cluster from Azure Resource Manager with a managed identity token from IMDS, preferring the
+/// private FQDN since that's the one nodes of a private cluster use.
+async fn fqdn_from_arm(arm: &ArmLookup, metadata: &InstanceMetadata) -> Result<String> {
+    let resource_id = match &arm.cluster_resource_id {
+        Some(id) => id.clone(),
+        None => cluster_resource_id(metadata)?,
+    };
+    let token = imds::get_msi_token(&arm.imds_host, &arm.resource, arm.client_id.as_deref(), &arm.retry_policy).await?;
+
+    let mut url = arm
+        .endpoint
+        .join(resource_id.trim_start_matches('/'))
+        .with_context(|| format!("'{}' is not a valid resource ID", resource_id))?;
+    url.query_pairs_mut().append_pair("api-version", MANAGED_CLUSTERS_API_VERSION);
+
+    debug!("Getting the managed cluster from {}", url);
+    let res = reqwest::Client::builder()
+        .timeout(arm.retry_policy.request_timeout)
+        .build()?
+        .get(url)
+        .bearer_auth(token)
+        .send()
+        .await
+        .context("failed to send the request to Azure Resource Manager")?;
+
+    if !res.status().is_success() {
+        return Err(anyhow!(
+            "Azure Resource Manager returned {} for {}: {}",
+            res.status().as_u16(),
+            resource_id,
+            res.text().await.unwrap_or_default()
+        ));
+    }
+
+    let cluster: ManagedCluster = serde_json::from_str(&res.text().await?)
+        .context("Azure Resource Manager returned an invalid managed cluster")?;
+    [cluster.properties.private_fqdn, cluster.properties.fqdn]
+        .into_iter()
+        .flatten()
+        .find(|f| !f.is_empty())
+        .ok_or_else(|| anyhow!("the managed cluster {} has no FQDN", resource_id))
+}
+
+/// Builds the resource ID of the managed cluster from the subscription and the tags AKS sets on the node.
+fn cluster_resource_id(metadata: &InstanceMetadata) -> Result<String> {
+    let tags = metadata.compute.tags();
+    match (tags.get(CLUSTER_RG_TAG), tags.get(CLUSTER_NAME_TAG)) {
+        (Some(rg), Some(name)) if !metadata.compute.subscription_id.is_empty() => Ok(format!(
+            "/subscriptions/{}/resourceGroups/{}/providers/Microsoft.ContainerService/managedClusters/{}",
+            metadata.compute.subscription_id, rg, name
+        )),
+        _ => Err(anyhow!(
+            "the instance metadata doesn't name the managed cluster (the {} and {} tags), use --cluster-resource-id to give it",
+            CLUSTER_RG_TAG,
+            CLUSTER_NAME_TAG
+        )),
+    }
+}
+
+fn is_fqdn(host: &str) -> bool {
+    !host.is_empty() && host.parse::<IpAddr>().is_err()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+    use wiremock::{
+        matchers::{header, method, path, query_param},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    #[tokio::test]
+    async fn fqdn_should_come_from_the_first_source_that_has_one() {
+        let sources = FqdnSources {
+            service_host: Some(String::from("10.0.0.1")),
+            kubeconfigs: vec![PathBuf::from("test/missing-kubeconfig"), PathBuf::from("test/kubeconfig.yaml")],
+            arm: None,
+        };
+
+        let fqdn = discover_ccp_fqdn(&sources, &InstanceMetadata::default()).await.unwrap();
+
+        // the current context points at the second cluster in the file
+        assert_eq!("myaks-abc123.hcp.eastus2.azmk8s.io", fqdn);
+
+        let in_cluster = FqdnSources {
+            service_host: Some(String::from("myaks-def456.hcp.westus.azmk8s.io")),
+            ..sources
+        };
+
+        assert_eq!(
+            "myaks-def456.hcp.westus.azmk8s.io",
+            discover_ccp_fqdn(&in_cluster, &InstanceMetadata::default()).await.unwrap()
+        );
+        assert!(discover_ccp_fqdn(&FqdnSources::default(), &InstanceMetadata::default()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn fqdn_should_be_looked_up_in_arm() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method("GET"))
+            .and(path("/metadata/identity/oauth2/token"))
+            .and(query_param("resource", "https://management.azure.com/"))
+            .and(header("Metadata", "true"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(r#"{"access_token": "token"}"#, "application/json"))
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path(
+                "/subscriptions/0000/resourceGroups/myrg/providers/Microsoft.ContainerService/managedClusters/myaks",
+            ))
+            .and(header("Authorization", "Bearer token"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(
+                r#"{"properties": {"fqdn": "myaks-abc123.hcp.eastus2.azmk8s.io", "privateFQDN": null}}"#,
+                "application/json",
+            ))
+            .mount(&mock_server)
+            .await;
+
+        let mut metadata = InstanceMetadata::default();
+        metadata.compute.subscription_id = String::from("0000");
+        metadata.compute.tags = String::from("aks-managed-cluster-name:myaks;aks-managed-cluster-rg:myrg");
+
+        let sources = FqdnSources {
+            arm: Some(ArmLookup {
+                endpoint: Url::parse(&mock_server.uri()).unwrap(),
+                resource: String::from("https://management.azure.com/"),
+                imds_host: mock_server.address().to_string(),
+                client_id: None,
+                cluster_resource_id: None,
+                retry_policy: RetryPolicy {
+                    initial_backoff: Duration::from_millis(1),
+                    ..Default::default()
+                },
+            }),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            "myaks-abc123.hcp.eastus2.azmk8s.io",
+            discover_ccp_fqdn(&sources, &metadata).await.unwrap()
+        );
+
+        let untagged = discover_ccp_fqdn(&sources, &InstanceMetadata::default()).await.unwrap_err();
+
+        assert!(untagged.to_string().contains("--cluster-resource-id"), "{}", untagged);
+    }
+}
@@ -1,11 +1,27 @@
-use std::env;
-use anyhow::{anyhow, Result};
+mod format;
+mod overlay;
+mod source;
+mod validate;
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
 use clap::ArgMatches;
 use serde::{Deserialize, Serialize};
+use tabled::{builder::Builder, settings::Style};
+
+use crate::{
+    conncheck::{AuditSummary, EgressGroupResult, EgressRuleResult, ReportHeader, RuleCounts},
+    outcome::RuntimeFailure,
+};
 
-use crate::conncheck::EgressGroupResult;
+pub use format::Format;
+pub use overlay::{AddRules, Overlay, RuleOverride, RuleSelector};
+pub use source::{convert, read_sources, DataSource, DEFAULT_DATA_DIR};
+pub use validate::{Diagnostic, InvalidEgressData, LoadedGroups};
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct EgressData {
     #[serde(rename = "egressVersion")]
     pub egress_version: String,
@@ -14,127 +30,662 @@ pub struct EgressData {
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct EgressGroup {
     pub enabled: bool,
     pub name: String,
     pub rules: Vec<EgressRule>,
-    //#[serde(rename = "required")]
-    //pub required_group: bool,
+    /// Whether this is egress every AKS cluster needs, as opposed to egress for optional features and add-ons.
+    /// Groups that don't say are treated as optional.
+    #[serde(rename = "required", default)]
+    pub required_group: bool,
+    /// The Azure cloud the group applies to. Groups without one apply to every cloud.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cloud: Option<Cloud>,
+}
+
+/// Azure clouds, named the way IMDS reports them in `compute.azEnvironment`.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum Cloud {
+    AzurePublicCloud,
+    AzureChinaCloud,
+    AzureUSGovernmentCloud,
+}
+
+impl Cloud {
+    /// Parses either the IMDS name for a cloud or one of the short names `public`, `china` (or `21vianet`) and
+    /// `usgov`, ignoring case.
+    pub fn parse(val: &str) -> Result<Self, String> {
+        match val.trim().to_ascii_lowercase().as_str() {
+            "azurepubliccloud" | "public" => Ok(Cloud::AzurePublicCloud),
+            "azurechinacloud" | "china" | "21vianet" => Ok(Cloud::AzureChinaCloud),
+            "azureusgovernmentcloud" | "usgov" | "usgovernment" => Ok(Cloud::AzureUSGovernmentCloud),
+            _ => Err(format!("'{}' is not one of 'public', 'china' or 'usgov'", val)),
+        }
+    }
+
+    /// The Azure Resource Manager endpoint for the cloud, which is also the resource to request tokens for.
+    pub fn resource_manager_endpoint(&self) -> &'static str {
+        match self {
+            Cloud::AzurePublicCloud => "https://management.azure.com/",
+            Cloud::AzureChinaCloud => "https://management.chinacloudapi.cn/",
+            Cloud::AzureUSGovernmentCloud => "https://management.usgovcloudapi.net/",
+        }
+    }
+}
+
+impl std::fmt::Display for Cloud {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct EgressRule {
     pub name: String,
     pub dst: String,
-    pub protocol: String,
-    pub port: String,
+    pub protocol: Protocol,
+    pub port: PortSpec,
     pub description: String,
     #[serde(rename = "requiredPrivate")]
     pub required_private: bool,
     #[serde(rename = "enabled")]
     pub rule_enabled: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeouts: Option<RuleTimeouts>,
+    #[serde(rename = "latencyThresholdMs", default, skip_serializing_if = "Option::is_none")]
+    pub latency_threshold_ms: Option<u64>,
+    /// Where the rule came from. This is filled in when the egress data is loaded rather than read from it.
+    #[serde(skip_deserializing, skip_serializing_if = "RuleOrigin::is_unknown")]
+    pub origin: RuleOrigin,
 }
 
-impl EgressData {
-    pub fn filter_groups(&mut self, selected: &Vec<&String>) {
-        if selected.len() == 1 {
-            match selected.get(0) {
-                Some(s) => {
-                    if s.eq_ignore_ascii_case("required-egress-only") {
-                        let data: Vec<EgressGroup> = self
-                            .groups
-                            .clone()
-                            .into_iter()
-                            // .filter(|grp| grp.required_group)
-                            .collect();
-
-                        self.groups = data;
-                    } else if s.eq_ignore_ascii_case("optional-egress-only") {
-                        let data: Vec<EgressGroup> = self
-                            .groups
-                            .clone()
-                            .into_iter()
-                            // .filter(|grp| !grp.required_group)
-                            .collect();
-
-                        self.groups = data;
-                    } else {
-                        ()
-                    }
+/// Where an effective rule came from: the file that defined it, and the overlays that changed it after that.
+#[derive(Clone, Debug, Default, Serialize, PartialEq, Eq)]
+pub struct RuleOrigin {
+    pub file: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub changes: Vec<RuleChange>,
+}
+
+/// The changes an overlay made to a rule, e.g. `dst` and `port`, or `disabled`.
+#[derive(Clone, Debug, Serialize, PartialEq, Eq)]
+pub struct RuleChange {
+    pub overlay: PathBuf,
+    pub changed: Vec<&'static str>,
+}
+
+impl RuleOrigin {
+    pub fn new(file: &Path) -> Self {
+        RuleOrigin {
+            file: Some(file.to_path_buf()),
+            changes: Vec::new(),
+        }
+    }
+
+    pub fn is_unknown(&self) -> bool {
+        self.file.is_none() && self.changes.is_empty()
+    }
+}
+
+/// Written as e.g. `egress-data/global-app-required.json, overlay.yaml (dst, port)`.
+impl std::fmt::Display for RuleOrigin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let file = self.file.as_ref().map(|p| p.display().to_string());
+        let changes = self
+            .changes
+            .iter()
+            .map(|c| format!("{} ({})", c.overlay.display(), c.changed.join(", ")));
+
+        let all: Vec<String> = file.into_iter().chain(changes).collect();
+        match all.is_empty() {
+            true => write!(f, "-"),
+            false => write!(f, "{}", all.join(", ")),
+        }
+    }
+}
+
+/// Per-rule overrides for the audit's probe timeouts, in milliseconds. Any value left out falls back to the value
+/// configured for the audit.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct RuleTimeouts {
+    #[serde(rename = "connectMs", default, skip_serializing_if = "Option::is_none")]
+    pub connect_ms: Option<u64>,
+    #[serde(rename = "handshakeMs", default, skip_serializing_if = "Option::is_none")]
+    pub handshake_ms: Option<u64>,
+    #[serde(rename = "responseMs", default, skip_serializing_if = "Option::is_none")]
+    pub response_ms: Option<u64>,
+}
+
+/// How a rule's destination is probed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Protocol {
+    Tcp,
+    Udp,
+    Http,
+    Https,
+}
+
+impl Protocol {
+    /// Parses a protocol name, ignoring case and surrounding whitespace.
+    pub fn parse(val: &str) -> Result<Self, String> {
+        match val.trim().to_ascii_lowercase().as_str() {
+            "tcp" => Ok(Protocol::Tcp),
+            "udp" => Ok(Protocol::Udp),
+            "http" => Ok(Protocol::Http),
+            "https" => Ok(Protocol::Https),
+            _ => Err(format!("'{}' is not one of 'tcp', 'udp', 'http' or 'https'", val)),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Protocol::Tcp => "tcp",
+            Protocol::Udp => "udp",
+            Protocol::Http => "http",
+            Protocol::Https => "https",
+        }
+    }
+}
+
+impl TryFrom<String> for Protocol {
+    type Error = String;
+
+    fn try_from(val: String) -> Result<Self, Self::Error> {
+        Protocol::parse(&val)
+    }
+}
+
+impl From<Protocol> for String {
+    fn from(protocol: Protocol) -> Self {
+        protocol.as_str().to_string()
+    }
+}
+
+impl std::fmt::Display for Protocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Upper bound on the number of ports a single rule expands to, so a typo like `1-65535` can't turn into a port scan.
+pub const MAX_PORTS_PER_RULE: usize = 64;
+
+/// The ports a rule is probed on: a single port, or a comma separated list of ports and inclusive ranges, e.g.
+/// `"443"`, `"443,9000"` or `"30000-30010"`. A bare JSON number is accepted for a single port too.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "RawPortSpec", into = "String")]
+pub struct PortSpec(Vec<(u16, u16)>);
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawPortSpec {
+    Number(u64),
+    Text(String),
+}
+
+impl PortSpec {
+    pub fn parse(val: &str) -> Result<Self, String> {
+        let mut ranges = Vec::new();
+        for part in val.split(',').map(str::trim) {
+            let (start, end) = match part.split_once('-') {
+                Some((start, end)) => (parse_port(start.trim(), val)?, parse_port(end.trim(), val)?),
+                None => {
+                    let port = parse_port(part, val)?;
+                    (port, port)
                 }
-                None => (),
+            };
+            if start > end {
+                return Err(format!("'{}' is not a valid port range in '{}', the start is after the end", part, val));
             }
-        } else {
-            let data: Vec<EgressGroup> = self
-                .groups
-                .clone()
-                .into_iter()
-                .filter(|grp| selected.contains(&&grp.name))
-                .collect();
-
-            self.groups = data;
+            ranges.push((start, end));
+        }
+
+        let spec = PortSpec(ranges);
+        match spec.ports().count() {
+            count if count > MAX_PORTS_PER_RULE => Err(format!(
+                "'{}' covers {} ports, a rule can cover at most {}",
+                val, count, MAX_PORTS_PER_RULE
+            )),
+            _ => Ok(spec),
         }
     }
+
+    /// Every port the spec covers, in the order they were given.
+    pub fn ports(&self) -> impl Iterator<Item = u16> + '_ {
+        self.0.iter().flat_map(|(start, end)| *start..=*end)
+    }
+}
+
+fn parse_port(part: &str, val: &str) -> Result<u16, String> {
+    match part.parse::<u16>() {
+        Ok(port) if port > 0 => Ok(port),
+        _ => Err(format!("'{}' is not a valid port in '{}', ports must be between 1 and 65535", part, val)),
+    }
+}
+
+impl From<u16> for PortSpec {
+    fn from(port: u16) -> Self {
+        PortSpec(vec![(port, port)])
+    }
+}
+
+impl TryFrom<RawPortSpec> for PortSpec {
+    type Error = String;
+
+    fn try_from(raw: RawPortSpec) -> Result<Self, Self::Error> {
+        match raw {
+            RawPortSpec::Number(port) => PortSpec::parse(&port.to_string()),
+            RawPortSpec::Text(val) => PortSpec::parse(&val),
+        }
+    }
+}
+
+impl From<PortSpec> for String {
+    fn from(spec: PortSpec) -> Self {
+        spec.to_string()
+    }
+}
+
+impl std::fmt::Display for PortSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let parts: Vec<String> = self
+            .0
+            .iter()
+            .map(|(start, end)| match start == end {
+                true => start.to_string(),
+                false => format!("{}-{}", start, end),
+            })
+            .collect();
+        f.write_str(&parts.join(","))
+    }
 }
 
+/// Selects every group marked as required egress.
+pub const REQUIRED_EGRESS_ONLY: &str = "required-egress-only";
+/// Selects every group that isn't marked as required egress.
+pub const OPTIONAL_EGRESS_ONLY: &str = "optional-egress-only";
+
+impl EgressData {
+    /// Keeps only the groups that were selected, either by name or with the `required-egress-only` and
+    /// `optional-egress-only` selectors. Names and selectors can be mixed.
+    pub fn filter_groups(&mut self, selected: &[&String]) {
+        let has_selector = |selector: &str| selected.iter().any(|s| s.eq_ignore_ascii_case(selector));
+        let required = has_selector(REQUIRED_EGRESS_ONLY);
+        let optional = has_selector(OPTIONAL_EGRESS_ONLY);
+
+        self.groups.retain(|grp| {
+            selected.contains(&&grp.name) || (required && grp.required_group) || (optional && !grp.required_group)
+        });
+    }
+
+    /// Drops the groups that belong to a different cloud, apart from any that were selected by name in `named`.
+    pub fn filter_cloud(&mut self, cloud: Cloud, named: &[&String]) {
+        self.groups
+            .retain(|grp| grp.cloud.map(|c| c == cloud).unwrap_or(true) || named.contains(&&grp.name));
+    }
+
+    /// Drops disabled groups, and disabled rules from the groups that are left.
+    pub fn filter_disabled(&mut self) {
+        self.groups.retain(|grp| grp.enabled);
+        self.groups.iter_mut().for_each(|grp| grp.rules.retain(|r| r.rule_enabled));
+    }
+}
+
+/// Reads every egress group from the data sources and applies the overlays to them, failing with all of the problems
+/// found if any file is invalid.
 #[tracing::instrument()]
-pub async fn load_egress_data() -> Result<EgressData> {
-    let mut egress_data = EgressData {
+pub async fn load_egress_data(sources: &[DataSource], overlays: &[DataSource]) -> Result<EgressData> {
+    let loaded = read_sources(sources, overlays)?;
+    let egress_version = loaded.egress_version.clone().unwrap_or_default();
+    let groups = loaded.into_groups()?;
+
+    Ok(EgressData {
         name: String::from("aks-egress"),
-        egress_version: String::from(""),
-        groups: Vec::new(),
+        egress_version,
+        groups,
+    })
+}
+
+/// Prints the audit results in the format selected with `-o/--output`, writing them to the `-f/--output-file` path
+/// instead of stdout when one is given.
+#[tracing::instrument(skip(header, results, matches))]
+pub async fn print_conn_results(
+    header: &ReportHeader,
+    results: &[EgressGroupResult],
+    matches: &ArgMatches,
+) -> Result<()> {
+    let rendered = match matches.get_one::<String>("format").map(|f| f.as_str()) {
+        Some("json") => render_json(header, results)?,
+        _ => render_table(header, results),
     };
-    let mut path = "";
-
-    let test_val = env::var("LOCAL_TEST");
-    match test_val {
-        Ok(val) => {
-            if val == String::from("true") {
-                path = "./egress-data";
-            } else {
-                log::debug!("LOCAL_TEST is not set to true, using /etc/egress-data as data directory");
-                path = "/etc/egress-data";
-            }
+
+    match matches.get_one::<String>("output-file-path") {
+        Some(path) => {
+            std::fs::write(path, rendered)
+                .with_context(|| RuntimeFailure(format!("failed to write results to {}", path)))?;
+            log::info!("Results were saved to {}", path);
         }
-        _ => {
-            log::debug!("LOCAL_TEST is not set, using /etc/egress-data as data directory");
-            path = "/etc/egress-data";
+        None => println!("{}", rendered),
+    }
+
+    Ok(())
+}
+
+/// Serializes the node the audit ran on and a summary of the audit, followed by the complete set of group results,
+/// including the rules that passed.
+fn render_json(header: &ReportHeader, results: &[EgressGroupResult]) -> Result<String> {
+    Ok(serde_json::to_string_pretty(&serde_json::json!({
+        "node": header,
+        "summary": AuditSummary::from_results(results),
+        "groups": results,
+    }))?)
+}
+
+/// Lays the results out one rule per row, with the time each probe stage took, followed by the counts for each group
+/// and the audit as a whole. A line naming the node the audit ran on comes first.
+fn render_table(header: &ReportHeader, results: &[EgressGroupResult]) -> String {
+    let mut builder = Builder::default();
+    builder.set_header(vec![
+        "Egress Group",
+        "Rule",
+        "Port",
+        "Result",
+        "Reason",
+        "DNS (ms)",
+        "Connect (ms)",
+        "TLS (ms)",
+        "First Byte (ms)",
+        "Total (ms)",
+        "Details",
+    ]);
+
+    results.iter().for_each(|g: &EgressGroupResult| {
+        g.rule_results.iter().for_each(|r: &EgressRuleResult| {
+            let reason = match (r.reason, r.failed_stage) {
+                (Some(reason), Some(stage)) => format!("{} ({})", to_label(&reason), to_label(&stage)),
+                (Some(reason), None) => to_label(&reason),
+                _ => String::new(),
+            };
+
+            let details = match (&r.err_msg, r.http_status) {
+                (Some(msg), _) => msg.clone(),
+                (None, Some(status)) => format!("HTTP {}", status),
+                (None, None) => String::new(),
+            };
+
+            builder.push_record(vec![
+                g.name.clone(),
+                r.name.clone(),
+                r.port.map(|p| p.to_string()).unwrap_or_default(),
+                match r.skip_reason {
+                    Some(skip_reason) => format!("{:?} ({})", r.result, skip_reason),
+                    None => format!("{:?}", r.result),
+                },
+                reason,
+                format_ms(r.timings.dns_ms),
+                format_ms(r.timings.connect_ms),
+                format_ms(r.timings.tls_ms),
+                format_ms(r.timings.first_byte_ms),
+                format_ms(Some(r.timings.total_ms())),
+                details,
+            ]);
+        });
+    });
+
+    let mut table = builder.build();
+    table.with(Style::modern());
+
+    format!("{}\n{}\n{}", render_header(header), table, render_summary_table(results))
+}
+
+/// Describes the node in one line. Fields are shown as `-` when the metadata didn't come from IMDS and so is missing
+/// them.
+fn render_header(header: &ReportHeader) -> String {
+    let or_dash = |val: &str| if val.is_empty() { String::from("-") } else { val.to_string() };
+    let or_none = |vals: &[String]| match vals.join(", ") {
+        joined if joined.is_empty() => String::from("-"),
+        joined => joined,
+    };
+
+    format!(
+        "Node: {} ({}) | Region: {} | Zone: {} | Subnet: {} | IP: {}",
+        or_dash(&header.node),
+        or_dash(&header.vm_size),
+        header.region,
+        header.zone.as_deref().unwrap_or("-"),
+        or_none(&header.subnets),
+        or_none(&header.private_ips)
+    )
+}
+
+fn render_summary_table(results: &[EgressGroupResult]) -> String {
+    let mut builder = Builder::default();
+    builder.set_header(vec!["Egress Group", "Passed", "Warned", "Failed", "Skipped", "Disabled", "Pass %"]);
+
+    let counts_record = |name: String, c: &RuleCounts| {
+        vec![
+            name,
+            c.passed.to_string(),
+            c.warned.to_string(),
+            c.failed.to_string(),
+            c.skipped.to_string(),
+            c.disabled.to_string(),
+            format!("{:.1}", c.pass_pct),
+        ]
+    };
+
+    for g in results {
+        builder.push_record(counts_record(g.name.clone(), &g.counts));
+    }
+    let summary = AuditSummary::from_results(results);
+    builder.push_record(counts_record(format!("Total ({} groups)", summary.groups), &summary.counts));
+
+    let mut table = builder.build();
+    table.with(Style::modern());
+
+    table.to_string()
+}
+
+fn format_ms(ms: Option<f64>) -> String {
+    ms.map(|ms| format!("{:.1}", ms)).unwrap_or_else(|| String::from("-"))
+}
+
+/// Uses the serialized name of an enum variant as its label so the table matches the JSON output.
+fn to_label<T: Serialize>(val: &T) -> String {
+    serde_json::to_value(val)
+        .ok()
+        .and_then(|v| v.as_str().map(String::from))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::conncheck::{ConnCheckResult, FailureReason, ProbeStage, ProbeTimings};
+
+    fn rule_result(name: &str, result: ConnCheckResult) -> EgressRuleResult {
+        EgressRuleResult {
+            port: Some(443),
+            result,
+            timings: ProbeTimings {
+                dns_ms: Some(1.25),
+                connect_ms: Some(2.5),
+                ..ProbeTimings::default()
+            },
+            ..EgressRuleResult::pass(name)
         }
     }
 
-    let egress_files = std::fs::read_dir(&path)?;
+    fn header() -> ReportHeader {
+        ReportHeader {
+            node: String::from("aks-nodepool1-vmss000000"),
+            region: String::from("eastus2"),
+            vm_size: String::from("Standard_D2s_v3"),
+            private_ips: vec![String::from("10.224.0.4")],
+            subnets: vec![String::from("10.224.0.0/16")],
+            ..Default::default()
+        }
+    }
 
-    egress_files.for_each(|entry| match entry {
-        Ok(de) => {
-            if de.file_type().unwrap().is_file() {
-                let p = de.path();
-                log::debug!("Current file name is {:#?}", p.file_name().unwrap());
-                if p.extension().unwrap() == "json" {
-                    let in_file = std::fs::File::open(p).unwrap();
-                    let buf = std::io::BufReader::new(in_file);
+    fn results() -> Vec<EgressGroupResult> {
+        vec![EgressGroupResult {
+            name: String::from("aks-core"),
+            enabled: true,
+            required: true,
+            counts: RuleCounts {
+                passed: 1,
+                failed: 1,
+                disabled: 2,
+                pass_pct: 50.0,
+                ..RuleCounts::default()
+            },
+            rule_results: vec![
+                EgressRuleResult {
+                    http_status: Some(404),
+                    ..rule_result("mcr", ConnCheckResult::Pass)
+                },
+                EgressRuleResult {
+                    err_msg: Some(String::from("connection refused")),
+                    reason: Some(FailureReason::ConnectionRefused),
+                    failed_stage: Some(ProbeStage::Connect),
+                    ..rule_result("apiserver", ConnCheckResult::Fail)
+                },
+            ],
+        }]
+    }
 
-                    let eg: EgressGroup = serde_json::from_reader(buf).unwrap();
-                    egress_data.groups.push(eg.clone());
-                } else {
-                    log::warn!("Found a non-JSON file in the egress data.");
-                }
-            } else {
-                log::debug!("Skipping non-file DirEntry {:#?}", de);
-            }
+    fn group(name: &str, required: bool) -> EgressGroup {
+        EgressGroup {
+            enabled: true,
+            name: name.to_string(),
+            rules: Vec::new(),
+            required_group: required,
+            cloud: None,
         }
-        Err(e) => {
-            anyhow!(
-                "An error occurred while attempting to read the path {} - err: {:?}",
-                path,
-                e
-            );
+    }
+
+    fn group_names(data: &EgressData) -> Vec<&str> {
+        data.groups.iter().map(|g| g.name.as_str()).collect()
+    }
+
+    #[test]
+    fn groups_should_filter_on_required_flag_and_name() {
+        let data = EgressData {
+            egress_version: String::new(),
+            name: String::from("test"),
+            groups: vec![group("core", true), group("gpu", false), group("monitor", false)],
+        };
+        let select = |selected: &[&str]| {
+            let selected: Vec<String> = selected.iter().map(|s| s.to_string()).collect();
+            let mut filtered = data.clone();
+            filtered.filter_groups(&selected.iter().collect::<Vec<&String>>());
+            filtered
+        };
+
+        assert_eq!(vec!["core"], group_names(&select(&[REQUIRED_EGRESS_ONLY])));
+        assert_eq!(vec!["gpu", "monitor"], group_names(&select(&[OPTIONAL_EGRESS_ONLY])));
+        assert_eq!(vec!["core", "monitor"], group_names(&select(&["required-egress-only", "monitor"])));
+        assert_eq!(vec!["gpu"], group_names(&select(&["gpu"])));
+    }
+
+    #[test]
+    fn groups_should_filter_on_cloud() {
+        let mut data = EgressData {
+            egress_version: String::new(),
+            name: String::from("test"),
+            groups: vec![
+                EgressGroup {
+                    cloud: Some(Cloud::AzurePublicCloud),
+                    ..group("global", true)
+                },
+                EgressGroup {
+                    cloud: Some(Cloud::AzureUSGovernmentCloud),
+                    ..group("usgov", true)
+                },
+                EgressGroup {
+                    cloud: Some(Cloud::AzureChinaCloud),
+                    ..group("21vianet", true)
+                },
+                group("gpu", false),
+            ],
+        };
+        let named = String::from("21vianet");
+
+        data.filter_cloud(Cloud::parse("AzureUSGovernmentCloud").unwrap(), &[&named]);
+
+        assert_eq!(vec!["usgov", "21vianet", "gpu"], group_names(&data));
+        assert_eq!(Ok(Cloud::AzureChinaCloud), Cloud::parse("China"));
+        assert!(Cloud::parse("AzureGermanCloud").is_err());
+    }
+
+    #[test]
+    fn audits_should_default_to_the_global_required_groups_on_public_cloud() {
+        let sources = [DataSource::Dir(PathBuf::from("egress-data"))];
+        let mut data = EgressData {
+            egress_version: String::new(),
+            name: String::from("test"),
+            groups: read_sources(&sources, &[]).unwrap().into_groups().unwrap(),
+        };
+        let required_only = String::from(REQUIRED_EGRESS_ONLY);
+
+        // the same selection `audit` makes without --group-name
+        data.filter_groups(&[&required_only]);
+        data.filter_cloud(Cloud::AzurePublicCloud, &[&required_only]);
+
+        let mut names = group_names(&data);
+        names.sort();
+        assert_eq!(vec!["global-app-required", "global-net-required"], names);
+    }
+
+    #[test]
+    fn ports_should_parse_lists_and_ranges() {
+        let spec: PortSpec = serde_json::from_str(r#""443, 9000-9002""#).unwrap();
+
+        assert_eq!(vec![443, 9000, 9001, 9002], spec.ports().collect::<Vec<u16>>());
+        assert_eq!("\"443,9000-9002\"", serde_json::to_string(&spec).unwrap());
+        assert_eq!(PortSpec::from(123), serde_json::from_str::<PortSpec>("123").unwrap());
+
+        for invalid in ["abc", "0", "65536", "443,", "9002-9000", "1-65535"] {
+            assert!(PortSpec::parse(invalid).is_err(), "{} should be rejected", invalid);
         }
-    });
 
-    Ok(egress_data)
-}
+        assert_eq!(Protocol::Https, serde_json::from_str::<Protocol>(r#"" HTTPS""#).unwrap());
+        assert!(serde_json::from_str::<Protocol>(r#""icmp""#).is_err());
+    }
+
+    #[test]
+    fn json_should_include_group_names_and_passing_rules() {
+        let json: serde_json::Value = serde_json::from_str(&render_json(&header(), &results()).unwrap()).unwrap();
+
+        let group = &json["groups"][0];
 
-#[tracing::instrument(skip(_results, _sm))]
-pub async fn print_conn_results(_results: &Vec<EgressGroupResult>, _sm: &ArgMatches) {
-    unimplemented!()
+        assert_eq!("aks-core", group["name"]);
+        assert_eq!(2, group["disabled"]);
+        assert_eq!("mcr", group["rule_results"][0]["name"]);
+        assert_eq!("Pass", group["rule_results"][0]["result"]);
+        assert_eq!("connection_refused", group["rule_results"][1]["reason"]);
+        assert_eq!(1, json["summary"]["groups"]);
+        assert_eq!(50.0, json["summary"]["pass_pct"]);
+        assert_eq!("aks-nodepool1-vmss000000", json["node"]["node"]);
+        assert_eq!("10.224.0.0/16", json["node"]["subnets"][0]);
+        assert!(json["node"].get("zone").is_none());
+    }
+
+    #[test]
+    fn table_should_list_every_rule_with_timings() {
+        let table = render_table(&header(), &results());
+
+        assert!(table.contains("aks-core"));
+        assert!(table.contains("HTTP 404"));
+        assert!(table.contains("connection_refused (connect)"));
+        assert!(table.contains("3.8"), "total time missing from:\n{}", table);
+        assert!(table.contains("Total (1 groups)"));
+        assert!(table.starts_with(
+            "Node: aks-nodepool1-vmss000000 (Standard_D2s_v3) | Region: eastus2 | Zone: - | Subnet: 10.224.0.0/16"
+        ));
+    }
 }
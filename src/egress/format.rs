@@ -0,0 +1,191 @@
+use std::{fmt, path::Path};
+
+use anyhow::{Context, Result};
+use serde::{de::DeserializeOwned, Serialize};
+
+/// The file formats egress data can be written in. They all hold the same [`super::EgressGroup`] and
+/// [`super::EgressData`] model, with the same field names.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Yaml,
+    Toml,
+}
+
+/// A parse error, with the (1-based) line and column it was found at.
+#[derive(Debug)]
+pub(super) struct ParseError {
+    pub(super) line: usize,
+    pub(super) column: usize,
+    pub(super) message: String,
+}
+
+impl Format {
+    /// Parses a format name, `yml` being accepted for YAML.
+    pub fn parse(val: &str) -> Result<Self, String> {
+        match val.trim().to_ascii_lowercase().as_str() {
+            "json" => Ok(Format::Json),
+            "yaml" | "yml" => Ok(Format::Yaml),
+            "toml" => Ok(Format::Toml),
+            _ => Err(format!("'{}' is not one of 'json', 'yaml' or 'toml'", val)),
+        }
+    }
+
+    /// The format a file's extension stands for. Files without an extension have no format of their own, and `None`
+    /// is also returned for extensions that aren't egress data.
+    pub fn from_extension(path: &Path) -> Option<Self> {
+        path.extension()
+            .and_then(|e| e.to_str())
+            .and_then(|e| Format::parse(e).ok())
+    }
+
+    /// The format of a data file, from its extension or, for files without one and stdin, from the content.
+    ///
+    /// JSON documents start with `{`, TOML documents with a `[table]` or a `key = value` line, and anything else is
+    /// read as YAML.
+    pub fn detect(path: &Path, raw: &str) -> Self {
+        if let Some(format) = Format::from_extension(path) {
+            return format;
+        }
+
+        let first = raw
+            .lines()
+            .map(str::trim)
+            .find(|l| !l.is_empty() && !l.starts_with('#'))
+            .unwrap_or_default();
+        let key_end = first.find([':', '=']).map(|i| &first[i..i + 1]);
+
+        if first.starts_with('{') {
+            Format::Json
+        } else if first.starts_with('[') || key_end == Some("=") {
+            Format::Toml
+        } else {
+            Format::Yaml
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Format::Json => "json",
+            Format::Yaml => "yaml",
+            Format::Toml => "toml",
+        }
+    }
+
+    /// Deserializes a document, reporting where in it the first problem is.
+    pub(super) fn deserialize<T: DeserializeOwned>(&self, raw: &str) -> Result<T, ParseError> {
+        match self {
+            Format::Json => serde_json::from_str(raw).map_err(|e| {
+                // serde_json appends the position to the message, it's reported separately here
+                let message = e.to_string();
+                let message = message.rsplit_once(" at line ").map(|(m, _)| m.to_string()).unwrap_or(message);
+                ParseError::new(e.line(), e.column(), message)
+            }),
+            Format::Yaml => serde_yaml::from_str(raw).map_err(|e| {
+                let (line, column) = e.location().map(|l| (l.line(), l.column())).unwrap_or((1, 1));
+                // serde_yaml appends the position to the message too
+                let message = e.to_string();
+                let message = message.rsplit_once(" at line ").map(|(m, _)| m.to_string()).unwrap_or(message);
+                ParseError::new(line, column, message)
+            }),
+            Format::Toml => toml::from_str(raw).map_err(|e| {
+                let (line, column) = e.span().map(|s| line_column(raw, s.start)).unwrap_or((1, 1));
+                ParseError::new(line, column, e.message().trim_end().to_string())
+            }),
+        }
+    }
+
+    /// Serializes a document in this format, ending it with a newline.
+    pub fn serialize<T: Serialize>(&self, value: &T) -> Result<String> {
+        let mut out = match self {
+            Format::Json => serde_json::to_string_pretty(value).context("failed to write the egress data as JSON")?,
+            Format::Yaml => serde_yaml::to_string(value).context("failed to write the egress data as YAML")?,
+            Format::Toml => toml::to_string_pretty(value).context("failed to write the egress data as TOML")?,
+        };
+        if !out.ends_with('\n') {
+            out.push('\n');
+        }
+
+        Ok(out)
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl ParseError {
+    fn new(line: usize, column: usize, message: String) -> Self {
+        ParseError {
+            line: line.max(1),
+            column: column.max(1),
+            message,
+        }
+    }
+}
+
+/// The (1-based) line and column of a byte offset in a document.
+pub(super) fn line_column(raw: &str, offset: usize) -> (usize, usize) {
+    let before = &raw[..offset.min(raw.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before.chars().rev().take_while(|c| *c != '\n').count() + 1;
+    (line, column)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::egress::EgressGroup;
+
+    const YAML: &str = "\
+enabled: true
+name: core
+required: true
+rules:
+  - name: mcr
+    dst: mcr.microsoft.com
+    protocol: https
+    port: 443
+    description: Microsoft Container Registry
+    requiredPrivate: true
+    enabled: true
+";
+
+    #[test]
+    fn formats_should_round_trip_the_same_group() {
+        let group: EgressGroup = Format::Yaml.deserialize(YAML).unwrap();
+        assert_eq!("core", group.name);
+        assert_eq!(vec![443], group.rules[0].port.ports().collect::<Vec<_>>());
+
+        for format in [Format::Json, Format::Yaml, Format::Toml] {
+            let raw = format.serialize(&group).unwrap();
+            assert_eq!(format, Format::detect(Path::new("-"), &raw), "{}", raw);
+
+            let parsed: EgressGroup = format.deserialize(&raw).unwrap();
+            assert_eq!(
+                serde_json::to_value(&group).unwrap(),
+                serde_json::to_value(&parsed).unwrap(),
+                "{}",
+                format
+            );
+        }
+    }
+
+    #[test]
+    fn errors_should_have_a_position() {
+        let bad = YAML.replace("protocol: https", "protocol: ftp");
+        let err = Format::Yaml.deserialize::<EgressGroup>(&bad).unwrap_err();
+        // serde_yaml reports invalid values at the start of the mapping holding them, i.e. the rule
+        assert_eq!((5, 5), (err.line, err.column));
+        assert!(err.message.contains("ftp"), "{}", err.message);
+
+        let toml = Format::Toml.serialize(&Format::Yaml.deserialize::<EgressGroup>(YAML).unwrap()).unwrap();
+        let bad = toml.replace("protocol = \"https\"", "protocol = \"ftp\"");
+        let err = Format::Toml.deserialize::<EgressGroup>(&bad).unwrap_err();
+        let line = bad.lines().position(|l| l.contains("ftp")).unwrap() + 1;
+        assert_eq!(line, err.line);
+        assert!(err.message.contains("ftp"), "{}", err.message);
+    }
+}
@@ -0,0 +1,325 @@
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use super::{
+    format::{Format, ParseError},
+    validate::{check_rules, check_dst, diagnostic, Locator},
+    EgressGroup, EgressRule, LoadedGroups, PortSpec, Protocol, RuleChange, RuleOrigin,
+};
+
+/// Changes made on top of the egress data, so shipped rules can be turned off, pointed somewhere else or added to
+/// without editing the files they come from.
+///
+/// Overlays are applied in the order they are given, each one to the result of the ones before it, so a later
+/// overlay wins over an earlier one. Within an overlay, groups are added first, then rules, then overrides are
+/// applied, and rules are disabled last.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct Overlay {
+    /// New groups, which can't share a name with an existing group.
+    #[serde(default)]
+    pub add_groups: Vec<EgressGroup>,
+    /// New rules for existing groups.
+    #[serde(default)]
+    pub add_rules: Vec<AddRules>,
+    /// Changes to the destination, port or protocol of existing rules.
+    #[serde(default, rename = "override")]
+    pub overrides: Vec<RuleOverride>,
+    /// Rules to disable.
+    #[serde(default)]
+    pub disable: Vec<RuleSelector>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct AddRules {
+    pub group: String,
+    pub rules: Vec<EgressRule>,
+}
+
+/// Picks out a rule by name, in one group or, without a group, in every group that has a rule by that name.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RuleSelector {
+    pub rule: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+}
+
+/// New values for the rules picked out by `rule` and `group`, as with [`RuleSelector`]. Values left out are kept.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RuleOverride {
+    pub rule: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dst: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<PortSpec>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub protocol: Option<Protocol>,
+}
+
+impl LoadedGroups {
+    /// Parses an overlay and applies it to the groups loaded so far. An overlay with any problem, including changes to
+    /// groups or rules that don't exist, isn't applied at all.
+    pub(super) fn apply_overlay(&mut self, path: &Path, raw: &str) {
+        let overlay: Overlay = match Format::detect(path, raw).deserialize(raw) {
+            Ok(overlay) => overlay,
+            Err(ParseError { line, column, message }) => {
+                self.diagnostics.push(diagnostic(path, (line, column), message));
+                return;
+            }
+        };
+
+        let mut groups = self.groups.clone();
+        let mut locator = Locator::new(raw);
+        let errors = self.diagnostics.len();
+        let problem = |at, message| diagnostic(path, at, message);
+
+        let mut added = Vec::new();
+        for mut group in overlay.add_groups {
+            let at = locator.next("name", &group.name);
+            let first = self.defined_in.get(&group.name).map(|p| p.as_path());
+            if let Some(first) = first.or(added.contains(&group.name).then_some(path)) {
+                let msg = format!("duplicate group name '{}', it is already defined in {}", group.name, first.display());
+                self.diagnostics.push(problem(at, msg));
+            }
+            check_rules(path, &group.name, &[], &group.rules, &mut locator, &mut self.diagnostics);
+
+            group.rules.iter_mut().for_each(|r| r.origin = RuleOrigin::new(path));
+            added.push(group.name.clone());
+            groups.push(group);
+        }
+
+        for add in overlay.add_rules {
+            let at = locator.next("group", &add.group);
+            let Some(group) = groups.iter_mut().find(|g| g.name == add.group) else {
+                self.diagnostics.push(problem(at, format!("there is no group named '{}' to add rules to", add.group)));
+                continue;
+            };
+            check_rules(path, &group.name, &group.rules, &add.rules, &mut locator, &mut self.diagnostics);
+
+            group.rules.extend(add.rules.into_iter().map(|mut r| {
+                r.origin = RuleOrigin::new(path);
+                r
+            }));
+        }
+
+        for change in overlay.overrides {
+            let at = locator.next("rule", &change.rule);
+            if let Some(dst) = &change.dst {
+                // advanced for every dst, so a later problem isn't reported at an earlier occurrence of the same value
+                let at = locator.next("dst", dst);
+                if let Some(msg) = check_dst(&change.rule, dst) {
+                    self.diagnostics.push(problem(at, msg));
+                }
+            }
+
+            let changed: Vec<&'static str> = [
+                change.dst.as_ref().map(|_| "dst"),
+                change.port.as_ref().map(|_| "port"),
+                change.protocol.as_ref().map(|_| "protocol"),
+            ]
+            .into_iter()
+            .flatten()
+            .collect();
+
+            match select(&mut groups, &change.rule, change.group.as_deref()) {
+                Ok(rules) => rules.into_iter().for_each(|rule| {
+                    if let Some(dst) = &change.dst {
+                        rule.dst = dst.clone();
+                    }
+                    if let Some(port) = &change.port {
+                        rule.port = port.clone();
+                    }
+                    if let Some(protocol) = change.protocol {
+                        rule.protocol = protocol;
+                    }
+                    record(rule, path, changed.clone());
+                }),
+                Err(msg) => self.diagnostics.push(problem(at, msg)),
+            }
+        }
+
+        for selector in overlay.disable {
+            let at = locator.next("rule", &selector.rule);
+            match select(&mut groups, &selector.rule, selector.group.as_deref()) {
+                Ok(rules) => rules.into_iter().for_each(|rule| {
+                    rule.rule_enabled = false;
+                    record(rule, path, vec!["disabled"]);
+                }),
+                Err(msg) => self.diagnostics.push(problem(at, msg)),
+            }
+        }
+
+        if self.diagnostics.len() == errors {
+            self.groups = groups;
+            self.defined_in.extend(added.into_iter().map(|name| (name, path.to_path_buf())));
+        }
+    }
+}
+
+/// The rules an overlay entry applies to, or why there aren't any.
+fn select<'a>(groups: &'a mut [EgressGroup], rule: &str, group: Option<&str>) -> Result<Vec<&'a mut EgressRule>, String> {
+    if let Some(name) = group {
+        if !groups.iter().any(|g| g.name == name) {
+            return Err(format!("there is no group named '{}'", name));
+        }
+    }
+
+    let rules: Vec<&mut EgressRule> = groups
+        .iter_mut()
+        .filter(|g| group.map(|name| g.name == name).unwrap_or(true))
+        .flat_map(|g| g.rules.iter_mut())
+        .filter(|r| r.name == rule)
+        .collect();
+
+    match (rules.is_empty(), group) {
+        (false, _) => Ok(rules),
+        (true, Some(name)) => Err(format!("there is no rule named '{}' in group '{}'", rule, name)),
+        (true, None) => Err(format!("there is no rule named '{}' in any group", rule)),
+    }
+}
+
+/// Records an overlay's change to a rule, merging it with an earlier change from the same overlay.
+fn record(rule: &mut EgressRule, overlay: &Path, changed: Vec<&'static str>) {
+    match rule.origin.changes.iter_mut().find(|c| c.overlay == overlay) {
+        Some(change) => {
+            for field in changed {
+                if !change.changed.contains(&field) {
+                    change.changed.push(field);
+                }
+            }
+        }
+        None => rule.origin.changes.push(RuleChange {
+            overlay: overlay.to_path_buf(),
+            changed,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::egress::{read_sources, DataSource};
+
+    const OVERLAY: &str = "\
+addGroups:
+  - name: internal
+    enabled: true
+    rules:
+      - name: artifact-mirror
+        dst: artifacts.contoso.internal
+        protocol: https
+        port: 443
+        description: Internal artifact mirror
+        requiredPrivate: true
+        enabled: true
+addRules:
+  - group: global-app-required
+    rules:
+      - name: mcr-mirror
+        dst: mcr.contoso.internal
+        protocol: https
+        port: 443
+        description: MCR pull-through cache
+        requiredPrivate: true
+        enabled: true
+override:
+  - rule: mcr-https
+    group: global-app-required
+    dst: mcr.contoso.internal
+    port: 8443
+disable:
+  - rule: ubuntu-changelogs
+";
+
+    fn shipped() -> LoadedGroups {
+        read_sources(&[DataSource::Dir(PathBuf::from("egress-data"))], &[]).unwrap()
+    }
+
+    fn rule<'a>(loaded: &'a LoadedGroups, group: &str, rule: &str) -> &'a EgressRule {
+        let group = loaded.groups.iter().find(|g| g.name == group).unwrap();
+        group.rules.iter().find(|r| r.name == rule).unwrap()
+    }
+
+    #[test]
+    fn overlays_should_add_override_and_disable_rules() {
+        let mut loaded = shipped();
+        let path = Path::new("overlay.yaml");
+        loaded.apply_overlay(path, OVERLAY);
+        assert!(loaded.diagnostics.is_empty(), "{:?}", loaded.diagnostics);
+
+        let added = rule(&loaded, "internal", "artifact-mirror");
+        assert_eq!("overlay.yaml", added.origin.to_string());
+        assert_eq!("overlay.yaml", rule(&loaded, "global-app-required", "mcr-mirror").origin.to_string());
+
+        let mcr = rule(&loaded, "global-app-required", "mcr-https");
+        assert_eq!("mcr.contoso.internal", mcr.dst);
+        assert_eq!("8443", mcr.port.to_string());
+        assert_eq!("egress-data/global-app-required.json, overlay.yaml (dst, port)", mcr.origin.to_string());
+
+        let changelogs = loaded
+            .groups
+            .iter()
+            .flat_map(|g| g.rules.iter())
+            .find(|r| r.name == "ubuntu-changelogs")
+            .unwrap();
+        assert!(!changelogs.rule_enabled);
+        assert!(changelogs.origin.to_string().ends_with("overlay.yaml (disabled)"), "{}", changelogs.origin);
+
+        // a later overlay wins over an earlier one
+        loaded.apply_overlay(Path::new("later.json"), r#"{"override": [{"rule": "mcr-https", "port": "443"}]}"#);
+        let mcr = rule(&loaded, "global-app-required", "mcr-https");
+        assert_eq!("443", mcr.port.to_string());
+        assert!(mcr.origin.to_string().ends_with("overlay.yaml (dst, port), later.json (port)"), "{}", mcr.origin);
+    }
+
+    #[test]
+    fn overlays_with_problems_should_not_be_applied() {
+        let mut loaded = shipped();
+        let before = serde_json::to_value(&loaded.groups).unwrap();
+
+        let bad = OVERLAY
+            .replace("rule: ubuntu-changelogs", "rule: ubuntu-changelog")
+            .replace("name: mcr-mirror", "name: mcr-https");
+        loaded.apply_overlay(Path::new("overlay.yaml"), &bad);
+
+        let messages: Vec<String> = loaded.diagnostics.iter().map(|d| d.to_string()).collect();
+        assert_eq!(
+            vec![
+                "overlay.yaml:15:9: duplicate rule name 'mcr-https' in group 'global-app-required'",
+                "overlay.yaml:28:5: there is no rule named 'ubuntu-changelog' in any group",
+            ],
+            messages
+        );
+        assert_eq!(before, serde_json::to_value(&loaded.groups).unwrap());
+    }
+
+    #[test]
+    fn override_problems_should_point_at_their_own_dst() {
+        let mut loaded = shipped();
+        let overlay = "\
+override:
+  - rule: mcr-https
+    dst: '{region}.mcr.contoso.internal'
+  - rule: aad-login
+    dst: '{region}.mcr.contoso.internal'
+  - rule: ntp
+    dst: '{cluster}.ntp.contoso.internal'
+  - rule: ubuntu-changelogs
+    dst: '{cluster}.ntp.contoso.internal'
+";
+        loaded.apply_overlay(Path::new("overlay.yaml"), overlay);
+
+        let messages: Vec<String> = loaded.diagnostics.iter().map(|d| d.to_string()).collect();
+        assert_eq!(2, messages.len(), "{:?}", messages);
+        assert!(messages[0].starts_with("overlay.yaml:7:5: rule 'ntp'"), "{}", messages[0]);
+        assert!(messages[1].starts_with("overlay.yaml:9:5: rule 'ubuntu-changelogs'"), "{}", messages[1]);
+    }
+}
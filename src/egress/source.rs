@@ -0,0 +1,236 @@
+use std::{
+    fmt, fs,
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+
+use super::{
+    validate::{check_document, diagnostic, InvalidEgressData, LoadedGroups},
+    EgressData, Format, RuleOrigin,
+};
+
+/// Where the egress data is read from when no source is given, matching the path the container image and the
+/// configmap in `deploy/` use.
+pub const DEFAULT_DATA_DIR: &str = "/etc/egress-data";
+
+/// How stdin is named in diagnostics.
+const STDIN: &str = "<stdin>";
+
+/// A place egress data is read from. Each file holds either a single egress group or a whole bundle (an object
+/// with `egressVersion`, `name` and `groups`), which is told apart by whether it has a `groups` field.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DataSource {
+    /// Every data file in a directory.
+    Dir(PathBuf),
+    File(PathBuf),
+    Stdin,
+}
+
+impl DataSource {
+    /// Picks the kind of source for a path given on the command line, where `-` stands for stdin.
+    pub fn from_path(path: &Path) -> Self {
+        if path == Path::new("-") {
+            DataSource::Stdin
+        } else if path.is_dir() {
+            DataSource::Dir(path.to_path_buf())
+        } else {
+            DataSource::File(path.to_path_buf())
+        }
+    }
+}
+
+impl fmt::Display for DataSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DataSource::Dir(path) | DataSource::File(path) => write!(f, "{}", path.display()),
+            DataSource::Stdin => write!(f, "{}", STDIN),
+        }
+    }
+}
+
+/// Reads and checks the egress data from every source in order, then applies the overlays on top of it in order.
+///
+/// Anything wrong with a file is collected as a diagnostic rather than returned as an error, so every problem can be
+/// reported at once. Sources that don't exist at all are an error.
+pub fn read_sources(sources: &[DataSource], overlays: &[DataSource]) -> Result<LoadedGroups> {
+    if sources.iter().chain(overlays).filter(|s| **s == DataSource::Stdin).count() > 1 {
+        return Err(anyhow!("stdin can only be used once, for either egress data or an overlay"));
+    }
+
+    let mut loaded = LoadedGroups::default();
+    for (path, raw) in read_files(sources)? {
+        loaded.files += 1;
+        match raw {
+            Ok(raw) => loaded.add(&path, &raw),
+            Err(e) => loaded.diagnostics.push(diagnostic(&path, (1, 1), format!("failed to read the file: {}", e))),
+        }
+    }
+    for (path, raw) in read_files(overlays)? {
+        loaded.files += 1;
+        match raw {
+            Ok(raw) => loaded.apply_overlay(&path, &raw),
+            Err(e) => loaded.diagnostics.push(diagnostic(&path, (1, 1), format!("failed to read the file: {}", e))),
+        }
+    }
+
+    Ok(loaded)
+}
+
+/// Reads every file from the sources in order, keeping any error reading a single file with its path.
+fn read_files(sources: &[DataSource]) -> Result<Vec<(PathBuf, io::Result<String>)>> {
+    let mut files = Vec::new();
+    for source in sources {
+        match source {
+            DataSource::Dir(path) => files.extend(data_files(path)?.into_iter().map(|f| {
+                let raw = fs::read_to_string(&f);
+                (f, raw)
+            })),
+            DataSource::File(path) if !path.is_file() => {
+                return Err(anyhow!("the egress data file {} does not exist", path.display()))
+            }
+            DataSource::File(path) => files.push((path.clone(), fs::read_to_string(path))),
+            DataSource::Stdin => files.push((PathBuf::from(STDIN), Ok(read_stdin()?))),
+        }
+    }
+
+    Ok(files)
+}
+
+/// Converts a single egress data file, or stdin, to another format. A file holding one group stays a single group and
+/// a bundle stays a bundle. The file is checked first, and isn't converted if there is anything wrong with it.
+pub fn convert(source: &DataSource, to: Format) -> Result<String> {
+    let (path, raw) = match source {
+        DataSource::File(path) => (
+            path.as_path(),
+            fs::read_to_string(path).with_context(|| format!("failed to read the file {}", path.display()))?,
+        ),
+        DataSource::Stdin => (Path::new(STDIN), read_stdin()?),
+        DataSource::Dir(path) => {
+            return Err(anyhow!("{} is a directory, only single files can be converted", path.display()))
+        }
+    };
+
+    let document = check_document(path, &raw, &Default::default()).map_err(InvalidEgressData)?;
+    match document.bundle_name {
+        Some(name) => to.serialize(&EgressData {
+            egress_version: document.egress_version.unwrap_or_default(),
+            name,
+            groups: document.groups,
+        }),
+        None => to.serialize(&document.groups[0]),
+    }
+}
+
+fn read_stdin() -> Result<String> {
+    let mut raw = String::new();
+    io::stdin()
+        .read_to_string(&mut raw)
+        .context("failed to read the egress data from stdin")?;
+
+    Ok(raw)
+}
+
+/// Lists the data files in a directory in name order, so groups are always listed the same way.
+///
+/// Files ending in `.json`, `.yaml`, `.yml` or `.toml` are read, as are files without an extension since that's how
+/// the keys of a mounted configmap show up. Hidden files are skipped, which also leaves out the `..data` links Kubernetes adds.
+fn data_files(path: &Path) -> Result<Vec<PathBuf>> {
+    if !path.is_dir() {
+        return Err(anyhow!("the egress data directory {} does not exist", path.display()));
+    }
+
+    let mut files = Vec::new();
+    for entry in fs::read_dir(path).with_context(|| format!("failed to read the directory {}", path.display()))? {
+        let file = entry.with_context(|| format!("failed to read the directory {}", path.display()))?.path();
+        let hidden = file
+            .file_name()
+            .map(|n| n.to_string_lossy().starts_with('.'))
+            .unwrap_or(true);
+
+        if hidden || !file.is_file() {
+            log::debug!("Skipping {}, it isn't a data file", file.display());
+        } else if file.extension().is_none() || Format::from_extension(&file).is_some() {
+            files.push(file);
+        } else {
+            log::warn!("Skipping {}, egress data files must end in .json, .yaml, .yml or .toml", file.display());
+        }
+    }
+    files.sort();
+
+    Ok(files)
+}
+
+impl LoadedGroups {
+    /// Parses and checks one file's worth of egress data, adding its groups or its problems.
+    fn add(&mut self, path: &Path, raw: &str) {
+        match check_document(path, raw, &self.defined_in) {
+            Ok(mut document) => {
+                if self.egress_version.is_none() {
+                    self.egress_version = document.egress_version;
+                }
+                for group in &mut document.groups {
+                    self.defined_in.insert(group.name.clone(), path.to_path_buf());
+                    group.rules.iter_mut().for_each(|r| r.origin = RuleOrigin::new(path));
+                }
+                self.groups.extend(document.groups);
+            }
+            Err(diagnostics) => self.diagnostics.extend(diagnostics),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sources_should_combine_and_detect_the_format() {
+        let dir = std::env::temp_dir().join(format!("egress-source-test-{}", std::process::id()));
+        fs::create_dir_all(dir.join("..data")).unwrap();
+        fs::copy("egress-data/gpu-app-required.json", dir.join("gpu")).unwrap();
+        fs::write(dir.join(".hidden.json"), "not json").unwrap();
+        fs::write(dir.join("notes.txt"), "not json either").unwrap();
+
+        let bundle = dir.join("bundle.json");
+        let gpu = fs::read_to_string("egress-data/gpu-app-required.json").unwrap();
+        let windows = fs::read_to_string("egress-data/windows-app-required.json").unwrap();
+        fs::write(
+            &bundle,
+            format!(r#"{{"egressVersion": "20230601", "name": "test", "groups": [{}, {}]}}"#, windows, gpu),
+        )
+        .unwrap();
+
+        let loaded = read_sources(&[DataSource::Dir(dir.clone())], &[]).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let names: Vec<&str> = loaded.groups.iter().map(|g| g.name.as_str()).collect();
+        assert_eq!(vec!["windows-app-required", "gpu-app-required"], names);
+        assert_eq!(Some("20230601"), loaded.egress_version.as_deref());
+        assert_eq!(2, loaded.files);
+        assert_eq!(1, loaded.diagnostics.len());
+        assert!(loaded.diagnostics[0].message.contains("duplicate group name 'gpu-app-required'"));
+        assert!(loaded.diagnostics[0].path.ends_with("gpu"), "{}", loaded.diagnostics[0]);
+
+        assert!(read_sources(&[DataSource::Stdin], &[DataSource::Stdin]).is_err());
+        assert!(convert(&DataSource::Dir(PathBuf::from("egress-data")), Format::Yaml).is_err());
+        assert!(read_sources(&[DataSource::File(PathBuf::from("egress-data/missing.json"))], &[]).is_err());
+    }
+
+    #[test]
+    fn convert_should_keep_the_data_across_formats() {
+        let original = PathBuf::from("egress-data/global-app-required.json");
+        let yaml = std::env::temp_dir().join(format!("egress-convert-test-{}.yaml", std::process::id()));
+        fs::write(&yaml, convert(&DataSource::File(original.clone()), Format::Yaml).unwrap()).unwrap();
+        let toml = yaml.with_extension("toml");
+        fs::write(&toml, convert(&DataSource::File(yaml.clone()), Format::Toml).unwrap()).unwrap();
+        let json = convert(&DataSource::File(toml.clone()), Format::Json).unwrap();
+        fs::remove_file(&yaml).unwrap();
+        fs::remove_file(&toml).unwrap();
+
+        let expected: serde_json::Value = serde_json::from_str(&fs::read_to_string(&original).unwrap()).unwrap();
+        let converted: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(expected, converted);
+    }
+}
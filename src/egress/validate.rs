@@ -0,0 +1,352 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use serde::Serialize;
+
+use super::{
+    format::{line_column, Format, ParseError},
+    EgressData, EgressGroup, EgressRule,
+};
+use crate::conncheck::{variables, KNOWN_VARIABLES};
+
+/// A problem found in an egress data file, with the (1-based) line and column it was found at.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Diagnostic {
+    pub path: PathBuf,
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}: {}", self.path.display(), self.line, self.column, self.message)
+    }
+}
+
+/// Returned when egress data fails validation, listing every problem rather than just the first.
+#[derive(Debug)]
+pub struct InvalidEgressData(pub Vec<Diagnostic>);
+
+impl fmt::Display for InvalidEgressData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "found {} problem(s) in the egress data", self.0.len())?;
+        self.0.iter().try_for_each(|d| write!(f, "\n  {}", d))
+    }
+}
+
+impl std::error::Error for InvalidEgressData {}
+
+/// The groups read from a set of egress data files, and the problems found in them.
+#[derive(Debug, Default)]
+pub struct LoadedGroups {
+    pub groups: Vec<EgressGroup>,
+    /// The `egressVersion` of the first bundle that was read, if any.
+    pub egress_version: Option<String>,
+    pub files: usize,
+    pub diagnostics: Vec<Diagnostic>,
+    /// The file each group was first defined in, to point duplicates at it.
+    pub(super) defined_in: HashMap<String, PathBuf>,
+}
+
+impl LoadedGroups {
+    /// Fails with every problem found if there were any.
+    pub fn into_groups(self) -> Result<Vec<EgressGroup>> {
+        match self.diagnostics.is_empty() {
+            true => Ok(self.groups),
+            false => Err(InvalidEgressData(self.diagnostics).into()),
+        }
+    }
+}
+
+/// The groups in a single data file.
+#[derive(Debug, Default)]
+pub(super) struct Document {
+    /// The bundle's `name`, `None` for a file holding a single group.
+    pub(super) bundle_name: Option<String>,
+    pub(super) egress_version: Option<String>,
+    pub(super) groups: Vec<EgressGroup>,
+}
+
+/// Parses a data file holding either a single egress group or a bundle of them, and checks the things the types
+/// can't: that group names are unique across everything loaded so far, that rule names are unique within a group,
+/// and that destinations only use known template variables.
+pub(super) fn check_document(
+    path: &Path,
+    raw: &str,
+    defined_in: &HashMap<String, PathBuf>,
+) -> Result<Document, Vec<Diagnostic>> {
+    let format = Format::detect(path, raw);
+    let parse_error = |e: ParseError| vec![diagnostic(path, (e.line, e.column), e.message)];
+
+    let value: serde_json::Value = format.deserialize(raw).map_err(parse_error)?;
+    let is_bundle = value.get("groups").is_some();
+
+    let document = match is_bundle {
+        true => format.deserialize::<EgressData>(raw).map(|data| Document {
+            bundle_name: Some(data.name),
+            egress_version: Some(data.egress_version).filter(|v| !v.is_empty()),
+            groups: data.groups,
+        }),
+        false => format.deserialize::<EgressGroup>(raw).map(|group| Document {
+            bundle_name: None,
+            egress_version: None,
+            groups: vec![group],
+        }),
+    }
+    .map_err(parse_error)?;
+
+    let mut locator = Locator::new(raw);
+    if let Some(name) = value.get("name").and_then(|n| n.as_str()).filter(|_| is_bundle) {
+        locator.next("name", name);
+    }
+
+    let mut diagnostics = Vec::new();
+    let mut local: HashMap<&str, ()> = HashMap::new();
+    for group in &document.groups {
+        let at = locator.next("name", &group.name);
+        let first = defined_in.get(&group.name).map(|p| p.display().to_string());
+        if first.is_some() || local.insert(group.name.as_str(), ()).is_some() {
+            let msg = format!(
+                "duplicate group name '{}', it is already defined in {}",
+                group.name,
+                first.unwrap_or_else(|| path.display().to_string())
+            );
+            diagnostics.push(diagnostic(path, at, msg));
+        }
+
+        check_rules(path, &group.name, &[], &group.rules, &mut locator, &mut diagnostics);
+    }
+
+    match diagnostics.is_empty() {
+        true => Ok(document),
+        false => Err(diagnostics),
+    }
+}
+
+/// Checks rules being added to a group: that their names are unique, both among themselves and among the rules the
+/// group already has, and that their destinations only use known template variables.
+pub(super) fn check_rules(
+    path: &Path,
+    group: &str,
+    existing: &[EgressRule],
+    rules: &[EgressRule],
+    locator: &mut Locator<'_>,
+    diagnostics: &mut Vec<Diagnostic>,
+) {
+    let mut names: HashMap<&str, ()> = existing.iter().map(|r| (r.name.as_str(), ())).collect();
+    for rule in rules {
+        let at = locator.next("name", &rule.name);
+        if names.insert(rule.name.as_str(), ()).is_some() {
+            let msg = format!("duplicate rule name '{}' in group '{}'", rule.name, group);
+            diagnostics.push(diagnostic(path, at, msg));
+        }
+
+        let at = locator.next("dst", &rule.dst);
+        if let Some(msg) = check_dst(&rule.name, &rule.dst) {
+            diagnostics.push(diagnostic(path, at, msg));
+        }
+    }
+}
+
+/// Describes what's wrong with a rule's destination: a bare `*`, which can't be probed, or template variables that
+/// aren't known.
+pub(super) fn check_dst(rule: &str, dst: &str) -> Option<String> {
+    if dst.trim() == "*" {
+        return Some(format!(
+            "rule '{}' has '*' as its destination, which can't be probed, use a template variable such as {{dns-server}} instead",
+            rule
+        ));
+    }
+
+    let unknown: Vec<String> = variables(dst)
+        .into_iter()
+        .filter(|v| !KNOWN_VARIABLES.contains(&v.as_str()))
+        .map(|v| format!("{{{}}}", v))
+        .collect();
+
+    match unknown.is_empty() {
+        true => None,
+        false => Some(format!(
+            "rule '{}' uses unknown template variable(s) {}, known variables are {}",
+            rule,
+            unknown.join(", "),
+            KNOWN_VARIABLES.join(", ")
+        )),
+    }
+}
+
+/// Finds where values are in a document. Every `key: value` pair is looked for in document order, so values that
+/// appear more than once are told apart by counting the earlier occurrences of the same pair.
+pub(super) struct Locator<'a> {
+    raw: &'a str,
+    seen: HashMap<(&'static str, String), usize>,
+}
+
+impl<'a> Locator<'a> {
+    pub(super) fn new(raw: &'a str) -> Self {
+        Locator {
+            raw,
+            seen: HashMap::new(),
+        }
+    }
+
+    /// The line and column of the next `key: value` pair.
+    pub(super) fn next(&mut self, key: &'static str, value: &str) -> (usize, usize) {
+        let count = self.seen.entry((key, value.to_string())).or_insert(0);
+        *count += 1;
+        locate(self.raw, key, value, *count - 1)
+    }
+}
+
+pub(super) fn diagnostic(path: &Path, (line, column): (usize, usize), message: String) -> Diagnostic {
+    Diagnostic {
+        path: path.to_path_buf(),
+        line,
+        column,
+        message,
+    }
+}
+
+/// Finds the line and column of the `nth` (0-based) `key: value` pair in a document, falling back to the start of
+/// the file if it can't be found.
+///
+/// This works for all of the formats: the key and value may be quoted or bare, and separated by `:` or `=`.
+fn locate(raw: &str, key: &str, value: &str, nth: usize) -> (usize, usize) {
+    let quoted = serde_json::to_string(value).unwrap_or_default();
+    let ident = |c: char| c.is_alphanumeric() || c == '_' || c == '-';
+
+    raw.match_indices(key)
+        .map(|(pos, _)| pos)
+        .filter(|&pos| {
+            let start = raw[..pos].strip_suffix(['"', '\'']).unwrap_or(&raw[..pos]);
+            let rest = &raw[pos + key.len()..];
+            let rest = rest.strip_prefix(['"', '\'']).unwrap_or(rest);
+
+            !start.ends_with(ident)
+                && rest
+                    .trim_start_matches([' ', '\t'])
+                    .strip_prefix([':', '='])
+                    .map(|rest| rest.trim_start_matches([' ', '\t']))
+                    .map(|rest| {
+                        let bare = rest.strip_prefix(['"', '\'']).unwrap_or(rest);
+                        rest.starts_with(&quoted)
+                            || bare
+                                .strip_prefix(value)
+                                .map(|end| end.is_empty() || end.starts_with(['"', '\'', ',', '}', '\r', '\n', ' ', '#']))
+                                .unwrap_or(false)
+                    })
+                    .unwrap_or(false)
+        })
+        .nth(nth)
+        .map(|pos| {
+            let pos = match raw[..pos].ends_with(['"', '\'']) {
+                true => pos - 1,
+                false => pos,
+            };
+            line_column(raw, pos)
+        })
+        .unwrap_or((1, 1))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::egress::{read_sources, DataSource};
+
+    const GROUP: &str = r#"{
+    "enabled": true,
+    "name": "core",
+    "rules": [
+        {
+            "name": "mcr",
+            "dst": "mcr.microsoft.com",
+            "protocol": "https",
+            "port": "443",
+            "description": "",
+            "requiredPrivate": true,
+            "enabled": true
+        },
+        {
+            "name": "mcr",
+            "dst": "{ccp-id}.{cluster}.azmk8s.io",
+            "protocol": "https",
+            "port": "443",
+            "description": "",
+            "requiredPrivate": true,
+            "enabled": true
+        }
+    ]
+}"#;
+
+    #[test]
+    fn diagnostics_should_point_at_the_problem() {
+        let path = Path::new("core.json");
+        let diagnostics = check_document(path, GROUP, &HashMap::new()).unwrap_err();
+
+        assert_eq!(2, diagnostics.len());
+        assert_eq!(
+            "core.json:15:13: duplicate rule name 'mcr' in group 'core'",
+            diagnostics[0].to_string()
+        );
+        assert_eq!((16, 13), (diagnostics[1].line, diagnostics[1].column));
+        assert!(diagnostics[1].message.contains("{cluster}"), "{}", diagnostics[1]);
+
+        let bad_port = GROUP.replacen(r#""port": "443""#, r#""port": "44x""#, 1);
+        let diagnostics = check_document(path, &bad_port, &HashMap::new()).unwrap_err();
+        assert_eq!((9, 25), (diagnostics[0].line, diagnostics[0].column));
+        assert!(diagnostics[0].message.contains("'44x' is not a valid port"), "{}", diagnostics[0]);
+
+        let unknown_field = GROUP.replacen(r#""dst""#, r#""destination""#, 1);
+        let diagnostics = check_document(path, &unknown_field, &HashMap::new()).unwrap_err();
+        assert_eq!(7, diagnostics[0].line);
+        assert!(diagnostics[0].message.contains("unknown field `destination`"), "{}", diagnostics[0]);
+
+        let wildcard = GROUP.replacen("mcr.microsoft.com", "*", 1);
+        let diagnostics = check_document(path, &wildcard, &HashMap::new()).unwrap_err();
+        assert_eq!((7, 13), (diagnostics[0].line, diagnostics[0].column));
+        assert!(diagnostics[0].message.contains("'*' as its destination"), "{}", diagnostics[0]);
+
+        let diagnostics = check_document(path, "{\n  \"enabled\": true,\n  \"name\": }", &HashMap::new()).unwrap_err();
+        assert_eq!((3, 11), (diagnostics[0].line, diagnostics[0].column));
+    }
+
+    #[test]
+    fn bundles_should_be_checked_across_groups() {
+        let path = Path::new("bundle.json");
+        let bundle = format!(r#"{{"egressVersion": "1", "name": "core", "groups": [{}, {}]}}"#, GROUP, GROUP);
+        let diagnostics = check_document(path, &bundle, &HashMap::new()).unwrap_err();
+
+        let duplicate: Vec<&Diagnostic> = diagnostics.iter().filter(|d| d.message.contains("group name")).collect();
+        assert_eq!(1, duplicate.len());
+        assert_eq!((26, 5), (duplicate[0].line, duplicate[0].column));
+
+        let defined_in = HashMap::from([("core".to_string(), PathBuf::from("core.json"))]);
+        let group = GROUP.replacen("\"mcr\"", "\"mcr-2\"", 1).replacen("{cluster}", "{ccp-id}", 1);
+        let diagnostics = check_document(path, &group, &defined_in).unwrap_err();
+        assert_eq!(
+            "bundle.json:3:5: duplicate group name 'core', it is already defined in core.json",
+            diagnostics[0].to_string()
+        );
+    }
+
+    #[test]
+    fn shipped_data_should_be_valid() {
+        let loaded = read_sources(&[DataSource::Dir(PathBuf::from("egress-data"))], &[]).unwrap();
+
+        assert!(loaded.diagnostics.is_empty(), "{}", InvalidEgressData(loaded.diagnostics));
+        assert_eq!(loaded.files, loaded.groups.len());
+
+        // groups without `required` are treated as optional, the shipped ones should say which they are
+        for entry in std::fs::read_dir("egress-data").unwrap() {
+            let path = entry.unwrap().path();
+            let raw: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+
+            assert!(raw["required"].is_boolean(), "{} is missing the required flag", path.display());
+        }
+    }
+}
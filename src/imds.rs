@@ -1,141 +1,386 @@
-use anyhow::{anyhow, Result};
+mod retry;
+
+use std::{collections::BTreeMap, fs, path::Path, time::Instant};
+
+use anyhow::{anyhow, Context, Result};
 use log::{error, info, debug, warn};
-use serde_json::Value;
+use serde::{Deserialize, Serialize};
+
+pub use retry::RetryPolicy;
+
+/// Where AKS writes the cloud provider config on every node.
+pub const AZURE_JSON_PATH: &str = "/etc/kubernetes/azure.json";
+
+/// Attached as context to errors from querying IMDS so they can be told apart from other failures, e.g. to pick the
+/// process exit code.
+#[derive(Debug)]
+pub struct ImdsUnavailable;
+
+impl std::fmt::Display for ImdsUnavailable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "failed to retrieve the instance metadata from IMDS")
+    }
+}
+
+/// The subset of the IMDS instance metadata the audit uses to pick and template rules and to label its reports.
+///
+/// Every field defaults when IMDS leaves it out, since older API versions and some VM types don't return all of them.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct InstanceMetadata {
+    pub compute: ComputeMetadata,
+    pub network: NetworkMetadata,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ComputeMetadata {
+    /// The Azure region, e.g. `eastus2`.
+    pub location: String,
+    /// The availability zone, empty when the VM isn't zonal.
+    pub zone: String,
+    pub name: String,
+    pub vm_size: String,
+    pub os_type: String,
+    pub offer: String,
+    pub sku: String,
+    pub resource_id: String,
+    pub subscription_id: String,
+    /// Tags as IMDS flattens them, `key1:value1;key2:value2`.
+    pub tags: String,
+    /// The Azure cloud, e.g. `AzurePublicCloud` or `AzureUSGovernmentCloud`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub az_environment: Option<String>,
+    pub os_profile: OsProfile,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct OsProfile {
+    pub computer_name: String,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NetworkMetadata {
+    pub interface: Vec<NetworkInterface>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct NetworkInterface {
+    pub ipv4: IpConfiguration,
+    pub ipv6: IpConfiguration,
+    pub mac_address: String,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct IpConfiguration {
+    pub ip_address: Vec<IpAddress>,
+    pub subnet: Vec<Subnet>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct IpAddress {
+    pub private_ip_address: String,
+    pub public_ip_address: String,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Subnet {
+    pub address: String,
+    pub prefix: String,
+}
+
+impl ComputeMetadata {
+    /// The node's hostname, falling back to the VM name when the OS profile isn't available.
+    pub fn node_name(&self) -> &str {
+        if self.os_profile.computer_name.is_empty() {
+            &self.name
+        } else {
+            &self.os_profile.computer_name
+        }
+    }
+
+    /// Splits the flattened `tags` string into name/value pairs.
+    pub fn tags(&self) -> BTreeMap<String, String> {
+        self.tags
+            .split(';')
+            .filter(|t| !t.is_empty())
+            .map(|t| match t.split_once(':') {
+                Some((name, value)) => (name.to_string(), value.to_string()),
+                None => (t.to_string(), String::new()),
+            })
+            .collect()
+    }
+}
+
+impl NetworkMetadata {
+    /// Every private IP address assigned to the VM, IPv4 first.
+    pub fn private_ips(&self) -> Vec<String> {
+        self.ip_configurations()
+            .flat_map(|c| c.ip_address.iter())
+            .map(|a| a.private_ip_address.clone())
+            .filter(|a| !a.is_empty())
+            .collect()
+    }
+
+    /// Every subnet the VM's interfaces are attached to, in CIDR notation, e.g. `172.16.0.0/20`.
+    pub fn subnet_prefixes(&self) -> Vec<String> {
+        self.ip_configurations()
+            .flat_map(|c| c.subnet.iter())
+            .filter(|s| !s.address.is_empty())
+            .map(|s| match s.prefix.as_str() {
+                "" => s.address.clone(),
+                prefix => format!("{}/{}", s.address, prefix),
+            })
+            .collect()
+    }
+
+    fn ip_configurations(&self) -> impl Iterator<Item = &IpConfiguration> {
+        self.interface.iter().map(|i| &i.ipv4).chain(self.interface.iter().map(|i| &i.ipv6))
+    }
+}
+
+/// The fields of the cloud provider config (`azure.json`) that stand in for the instance metadata.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct CloudProviderConfig {
+    cloud: String,
+    location: String,
+}
+
+/// Builds the instance metadata from the cloud provider config on an AKS node, for when IMDS can't be reached.
+///
+/// Only the region and cloud are known this way, so the rest of the metadata is left empty.
+pub fn metadata_from_azure_json(path: &Path) -> Result<InstanceMetadata> {
+    let raw = fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))?;
+    let config: CloudProviderConfig =
+        serde_json::from_str(&raw).with_context(|| format!("{} is not a valid cloud provider config", path.display()))?;
+
+    if config.location.is_empty() {
+        return Err(anyhow!("{} doesn't set the location of the cluster", path.display()));
+    }
+
+    let mut metadata = InstanceMetadata::default();
+    metadata.compute.location = config.location;
+    metadata.compute.az_environment = Some(config.cloud).filter(|c| !c.is_empty());
+
+    Ok(metadata)
+}
 
-const MAX_RETRY_COUNT: u8 = 5;
+/// Queries IMDS for the instance metadata, falling back to the cloud provider config at `azure_json` if IMDS can't be
+/// reached or doesn't return usable metadata.
+pub async fn get_instance_metadata_or_fallback(imds_host: &str, azure_json: &Path) -> Result<InstanceMetadata> {
+    match get_instance_metadata(imds_host).await {
+        Ok(metadata) => Ok(metadata),
+        Err(imds_err) => {
+            warn!(
+                "Failed to query IMDS, falling back to the cloud provider config at {}: {:#}",
+                azure_json.display(),
+                imds_err
+            );
+            metadata_from_azure_json(azure_json).map_err(|e| {
+                imds_err.context(format!("falling back to {} failed too: {:#}", azure_json.display(), e))
+            })
+        }
+    }
+}
 
 /// Queries Azure IMDS for the region of the deployed VMs.
 /// Short and sweet, bailing quick if something weird happens.
 #[tracing::instrument()]
 pub async fn get_region(imds_host: &str) -> Result<String> {
-    info!("Querying IMDS for the Azure region the node is deployed in. This is used to build the URLs for the connectivity checks.");
-    let client = reqwest::Client::new();
+    Ok(get_instance_metadata(imds_host).await?.compute.location)
+}
 
-    let mut is_retriable = true;
-    let mut retry_count: u8 = 0;
-    let mut region: String = String::from("");
+/// Queries Azure IMDS for the metadata of the VM the audit is running on, retrying with the default policy.
+#[tracing::instrument()]
+pub async fn get_instance_metadata(imds_host: &str) -> Result<InstanceMetadata> {
+    get_instance_metadata_with_policy(imds_host, &RetryPolicy::default()).await
+}
 
+/// Queries Azure IMDS for the metadata of the VM the audit is running on.
+///
+/// Throttling (429), transient server errors, `410 Gone` and failures to connect are retried according to `policy`.
+/// Any other error response is returned straight away, since retrying won't change it.
+#[tracing::instrument()]
+pub async fn get_instance_metadata_with_policy(imds_host: &str, policy: &RetryPolicy) -> Result<InstanceMetadata> {
+    info!("Querying IMDS for the Azure region the node is deployed in. This is used to build the URLs for the connectivity checks.");
     let imds_url = format!("http://{}/metadata/instance?api-version=2021-12-13", imds_host);
 
-    while is_retriable {
-        debug!("Attempting to get IMDS response for VM from {}", imds_url);
-        let res = client
-            .get(&imds_url)
-            .header("Metadata", "true")
-            .send()
-            .await?;
+    parse_metadata(&get_with_retry(&imds_url, policy).await?)
+}
+
+/// Requests a token for `resource` (e.g. `https://management.azure.com/`) from the managed identity endpoint of IMDS.
+/// `client_id` picks a user-assigned identity, such as the kubelet identity on AKS nodes.
+#[tracing::instrument(skip(policy))]
+pub async fn get_msi_token(
+    imds_host: &str,
+    resource: &str,
+    client_id: Option<&str>,
+    policy: &RetryPolicy,
+) -> Result<String> {
+    #[derive(Deserialize)]
+    struct TokenResponse {
+        access_token: String,
+    }
 
-        if res.status().is_client_error() || res.status().is_server_error() {
-            match res.status().as_u16() {
+    let mut token_url = reqwest::Url::parse(&format!("http://{}/metadata/identity/oauth2/token", imds_host))?;
+    token_url
+        .query_pairs_mut()
+        .append_pair("api-version", "2018-02-01")
+        .append_pair("resource", resource);
+    if let Some(client_id) = client_id {
+        token_url.query_pairs_mut().append_pair("client_id", client_id);
+    }
+
+    let payload = get_with_retry(token_url.as_str(), policy)
+        .await
+        .context("failed to get a managed identity token from IMDS")?;
+    let token: TokenResponse =
+        serde_json::from_str(&payload).context("IMDS returned a token response without an access token")?;
+
+    Ok(token.access_token)
+}
+
+/// Sends a GET to an IMDS endpoint and returns the response body, retrying according to `policy`.
+async fn get_with_retry(imds_url: &str, policy: &RetryPolicy) -> Result<String> {
+    let client = reqwest::Client::builder().timeout(policy.request_timeout).build()?;
+    let started = Instant::now();
+    let mut retries: u32 = 0;
+
+    loop {
+        debug!("Attempting to get IMDS response for VM from {}", imds_url);
+        let (failure, retry_after, deadline) = match client.get(imds_url).header("Metadata", "true").send().await {
+            Err(e) if e.is_connect() || e.is_timeout() => (format!("failed to connect to IMDS: {}", e), None, policy.deadline),
+            Err(e) => return Err(e).context("failed to send the request to IMDS"),
+            Ok(res) => match res.status().as_u16() {
+                200..=299 => return Ok(res.text().await?),
                 400 => {
-                    error!("IMDS query is missing the Metadata header or ")
-                },
-                404|405 => {
+                    // The Metadata header is missing or the request is malformed, retrying the same request won't help.
+                    error!("IMDS rejected the request as malformed.");
+                    return Err(anyhow!(
+                        "IMDS query failed with status code 400, the request is missing the Metadata header or is malformed. Response body: {:?}",
+                        res.text().await?
+                    ));
+                }
+                404 | 405 => {
                     // Resource not found or method not allowed. No point in attempting a retry
                     // so return an error and exit.
-                    error!("VM instance metadata was not found or the GET method was not accepted by IMDS.");
+                    error!("The IMDS resource was not found or the GET method was not accepted by IMDS.");
                     return Err(anyhow!(
                         "IMDS query failed with status code {}, retry is not applicable. Response body: {:?}",
                         res.status().as_u16(),
                         res.text().await?
                     ));
-                },
-                410|429|500 => {
-                    // These three error codes represent:
-                    //  - 410 - Retry after some time with a max backoff of 70 seconds
+                }
+                status @ (410 | 429 | 500 | 502 | 503 | 504) => {
+                    // These represent:
+                    //  - 410 - Retry after some time with a max of 70 seconds
                     //  - 429 API rate limits have been exceeded (5 requests per second as documented at https://learn.microsoft.com/en-us/azure/virtual-machines/instance-metadata-service?tabs=linux#rate-limiting)
-                    //  - 500 - Internal server error, retry after some time but no max backoff specified
-                    //
-                    // Log the response status we got with appropriate messaging and then try again.
-                    warn!(
-                        "IMDS returned a {} response, retrying after some time.",
-                        res.status().as_u16()
-                    );
-
-                    if retry_count > MAX_RETRY_COUNT {
-                        error!("Retry count exceeded. Returning error and exiting.");
-                        return Err(anyhow!(
-                            "Retry count exceeded."
-                        ));
-                    }
-
-                    // Increment the retry count and calculate the sleep duration (10 * retry count, up to max retry count of 5)
-                    retry_count += 1;
-                    let sleep_dur = 10 * retry_count;
-
-                    tokio::time::sleep(std::time::Duration::from_secs(sleep_dur.into())).await;
-                    continue;
+                    //  - 5xx - IMDS is briefly unavailable, retry after some time but no max backoff specified
+                    let deadline = match status {
+                        410 => policy.gone_deadline.min(policy.deadline),
+                        _ => policy.deadline,
+                    };
+                    (format!("IMDS returned a {} response", status), retry::retry_after(&res), deadline)
                 }
-                _ => {
+                status => {
                     // Unexpected HTTP status code from IMDS, log and error out.
                     error!("Unexpected HTTP status code from IMDS, logging error details and exiting.");
                     return Err(anyhow!(
-                        "Unexpected HTTP status code from IMDS, logging error details and exiting. Status: {}, response body: {:?}",
-                        res.status().as_u16(),
+                        "Unexpected HTTP status code from IMDS. Status: {}, response body: {:?}",
+                        status,
                         res.text().await?
                     ));
                 }
-            }
-            // handle an issue with the client making the request
-            if res.status().is_client_error() {
-                error!("Bad client request made to IMDS, logging error details and exiting.");
-                return Err(anyhow!(
-                    "Request to IMDS for instance metadata returned a client error response. Status: {}, response body: {:?}",
-                    res.status().as_u16(),
-                    res.text().await?
-                ));
-            }
-
-            if res.status().is_server_error() {
-                error!("Bad server response from IMDS, logging error details and exiting.");
-                return Err(anyhow!(
-                    "Request to IMDS for instance metadata returned a server error response. Status: {}, response body: {:?}",
-                    res.status().as_u16(),
-                    res.text().await?
-                ));
-            }
+            },
+        };
+
+        retries += 1;
+        let delay = policy.delay(retries, retry_after);
+        let elapsed = started.elapsed();
+
+        if retries > policy.max_retries || elapsed + delay > deadline {
+            error!("Giving up on IMDS after {} attempts.", retries);
+            return Err(anyhow!(
+                "gave up on IMDS after {} attempts over {:.1?}, last error: {}",
+                retries,
+                elapsed,
+                failure
+            ));
         }
 
-        let res_payload = res.text().await?;
-        is_retriable = false;
+        warn!(
+            "{}, retrying in {:.1?} (retry {} of {}).",
+            failure,
+            delay,
+            retries,
+            policy.max_retries
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
 
-        debug!("IMDS response: {:?}", res_payload);
-        let val: Value = serde_json::from_str(res_payload.as_str())?;
-        let rval = val["compute"]["location"].as_str().unwrap();
-        region = String::from(rval);
+fn parse_metadata(payload: &str) -> Result<InstanceMetadata> {
+    debug!("IMDS response: {:?}", payload);
+    let metadata: InstanceMetadata = serde_json::from_str(payload)
+        .with_context(|| format!("IMDS returned a response that isn't instance metadata: {:?}", payload))?;
 
-        if region.is_empty() {
-            error!("Azure region was not returned in the IMDS response - response received: {:?}", res_payload);
-            return Err(anyhow!(
+    if metadata.compute.location.is_empty() {
+        error!("Azure region was not returned in the IMDS response - response received: {:?}", payload);
+        return Err(anyhow!(
             "Azure region was not returned in the IMDS response - response received: {:?}",
-            res_payload
+            payload
         ));
-        }
     }
 
-    info!("IMDS query successful, region: {}", region);
+    info!(
+        "IMDS query successful, node: {}, region: {}, zone: {:?}, environment: {:?}",
+        metadata.compute.node_name(),
+        metadata.compute.location,
+        metadata.compute.zone,
+        metadata.compute.az_environment
+    );
 
-    Ok(region)
+    Ok(metadata)
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use anyhow::Result;
     use wiremock::{
         MockServer, Mock, ResponseTemplate,
         matchers::{method, path, header},
     };
 
-    use std::fs;
-    use std::path;
+    use std::{fs, time::Duration};
+
+    fn fast_policy() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 5,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(5),
+            deadline: Duration::from_secs(10),
+            gone_deadline: Duration::from_secs(10),
+            request_timeout: Duration::from_secs(1),
+        }
+    }
+
+    async fn request_count(mock_server: &MockServer) -> usize {
+        mock_server.received_requests().await.map(|r| r.len()).unwrap_or_default()
+    }
 
     #[tokio::test]
     async fn client_should_extract_region_from_successful_response() {
         let imds_resp = fs::read_to_string(std::path::Path::new("test/imds_resp.json")).unwrap();
         let mock_server = MockServer::start().await;
 
-        let mock = Mock::given(method("GET"))
+        Mock::given(method("GET"))
             .and(path("/metadata/instance"))
             .and(header("Metadata", "true"))
             .respond_with(ResponseTemplate::new(200)
@@ -144,26 +389,94 @@ mod test {
             .mount(&mock_server)
             .await;
 
-        let region = get_region(&mock_server.address().to_string().as_str()).await.unwrap();
+        let region = get_region(mock_server.address().to_string().as_str()).await.unwrap();
 
         assert_eq!("eastus2", region.as_str());
+
+        let metadata = get_instance_metadata(mock_server.address().to_string().as_str()).await.unwrap();
+
+        assert_eq!(Some("AzurePublicCloud"), metadata.compute.az_environment.as_deref());
     }
 
     #[tokio::test]
     async fn client_should_retry_on_retriable_error() {
-        let imds_resp = fs::read_to_string(std::path::Path::new("test/imds_resp.json")).unwrap();
         let mock_server = MockServer::start().await;
 
-        let mock = Mock::given(method("GET"))
+        Mock::given(method("GET"))
             .and(path("/metadata/instance"))
             .and(header("Metadata", "true"))
             .respond_with(ResponseTemplate::new(500))
             .mount(&mock_server)
             .await;
 
-        let region = get_region(&mock_server.address().to_string().as_str()).await;
+        let metadata = get_instance_metadata_with_policy(&mock_server.address().to_string(), &fast_policy()).await;
 
+        assert!(metadata.is_err());
+        assert_eq!(6, request_count(&mock_server).await);
+    }
 
+    #[tokio::test]
+    async fn client_should_honor_retry_after() {
+        let imds_resp = fs::read_to_string(std::path::Path::new("test/imds_resp.json")).unwrap();
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method("GET"))
+            .and(path("/metadata/instance"))
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "0"))
+            .up_to_n_times(2)
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/metadata/instance"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(imds_resp.as_str(), "application/json"))
+            .mount(&mock_server)
+            .await;
+
+        // the backoff alone would run past the deadline, so this only succeeds if Retry-After is used instead
+        let policy = RetryPolicy {
+            initial_backoff: Duration::from_secs(60),
+            ..fast_policy()
+        };
+        let metadata = get_instance_metadata_with_policy(&mock_server.address().to_string(), &policy).await.unwrap();
+
+        assert_eq!("eastus2", metadata.compute.location);
+        assert_eq!(3, request_count(&mock_server).await);
+    }
+
+    #[tokio::test]
+    async fn client_should_stop_retrying_gone_at_its_deadline() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method("GET"))
+            .and(path("/metadata/instance"))
+            .respond_with(ResponseTemplate::new(410))
+            .mount(&mock_server)
+            .await;
+
+        let policy = RetryPolicy {
+            gone_deadline: Duration::ZERO,
+            ..fast_policy()
+        };
+        let err = get_instance_metadata_with_policy(&mock_server.address().to_string(), &policy)
+            .await
+            .unwrap_err();
+
+        assert!(err.to_string().contains("410"), "{}", err);
+        assert_eq!(1, request_count(&mock_server).await);
+    }
+
+    #[tokio::test]
+    async fn client_should_retry_connection_errors() {
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let policy = RetryPolicy {
+            max_retries: 2,
+            ..fast_policy()
+        };
+
+        let err = get_instance_metadata_with_policy(&closed.to_string(), &policy).await.unwrap_err();
+
+        assert!(err.to_string().contains("after 3 attempts"), "{}", err);
+        assert!(err.to_string().contains("failed to connect to IMDS"), "{}", err);
     }
 
     #[tokio::test]
@@ -171,7 +484,7 @@ mod test {
         let imds_resp = fs::read_to_string(std::path::Path::new("test/imds_resp.json")).unwrap();
         let mock_server = MockServer::start().await;
 
-        let mock = Mock::given(method("GET"))
+        Mock::given(method("GET"))
             .and(path("/metadata/instance"))
             .and(header("Metadata", "true"))
             .respond_with(ResponseTemplate::new(400)
@@ -186,5 +499,62 @@ mod test {
             .header("Metadata", "true")
             .send()
             .await.unwrap();
+
+        assert_eq!(400, res.status().as_u16());
+
+        let err = get_instance_metadata_with_policy(&mock_server.address().to_string(), &fast_policy())
+            .await
+            .unwrap_err();
+
+        assert!(err.to_string().contains("status code 400"), "{}", err);
+        assert_eq!(2, request_count(&mock_server).await);
+    }
+
+    #[test]
+    fn metadata_should_parse_into_typed_fields() {
+        let imds_resp = fs::read_to_string(std::path::Path::new("test/imds_resp.json")).unwrap();
+        let metadata: InstanceMetadata = serde_json::from_str(&imds_resp).unwrap();
+
+        assert_eq!("vmssname-id-vmss000000", metadata.compute.node_name());
+        assert_eq!("Standard_B2s", metadata.compute.vm_size);
+        assert_eq!("Linux", metadata.compute.os_type);
+        assert_eq!("", metadata.compute.zone);
+        assert!(metadata.compute.tags().is_empty());
+        assert_eq!(vec!["172.16.0.1"], metadata.network.private_ips());
+        assert_eq!(vec!["172.16.0.0/20"], metadata.network.subnet_prefixes());
+
+        let sparse: InstanceMetadata =
+            serde_json::from_str(r#"{"compute": {"location": "westus", "name": "vm0", "tags": "env:prod;team"}}"#).unwrap();
+
+        assert_eq!("vm0", sparse.compute.node_name());
+        assert!(sparse.network.private_ips().is_empty());
+        assert_eq!(Some("prod"), sparse.compute.tags().get("env").map(|v| v.as_str()));
+        assert_eq!(Some(""), sparse.compute.tags().get("team").map(|v| v.as_str()));
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn metadata_should_fall_back_to_azure_json() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method("GET"))
+            .and(path("/metadata/instance"))
+            .respond_with(ResponseTemplate::new(404))
+            .mount(&mock_server)
+            .await;
+
+        let imds_host = mock_server.address().to_string();
+        let metadata = get_instance_metadata_or_fallback(&imds_host, Path::new("test/azure.json"))
+            .await
+            .unwrap();
+
+        assert_eq!("westeurope", metadata.compute.location);
+        assert_eq!(Some("AzurePublicCloud"), metadata.compute.az_environment.as_deref());
+
+        let err = get_instance_metadata_or_fallback(&imds_host, Path::new("test/missing-azure.json"))
+            .await
+            .unwrap_err();
+
+        assert!(format!("{:#}", err).contains("status code 404"), "{:#}", err);
+        assert!(format!("{:#}", err).contains("test/missing-azure.json"), "{:#}", err);
+    }
+}
@@ -0,0 +1,100 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    time::Duration,
+};
+
+use reqwest::{header::RETRY_AFTER, Response};
+
+/// How IMDS requests are retried when IMDS is throttling, briefly unavailable or can't be connected to.
+///
+/// Retries back off exponentially with jitter, so several nodes auditing at once don't retry in lockstep, and stop
+/// once either `max_retries` or the overall `deadline` is reached, whichever comes first.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Retries after the first attempt.
+    pub max_retries: u32,
+    /// Backoff before the first retry, doubled for each retry after it.
+    pub initial_backoff: Duration,
+    /// Upper bound on the backoff between two attempts. A `Retry-After` from IMDS is used as is.
+    pub max_backoff: Duration,
+    /// Time from the first attempt after which no more retries are made.
+    pub deadline: Duration,
+    /// Shorter deadline for `410 Gone`, which IMDS documents as worth retrying for up to 70 seconds.
+    pub gone_deadline: Duration,
+    /// Timeout for each individual request, so a blackholed IMDS counts as a failed attempt rather than a hang.
+    pub request_timeout: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 5,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(30),
+            deadline: Duration::from_secs(120),
+            gone_deadline: Duration::from_secs(70),
+            request_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The delay before retry number `retry` (starting at 1), honoring the `Retry-After` IMDS sent if there was one.
+    pub fn delay(&self, retry: u32, retry_after: Option<Duration>) -> Duration {
+        retry_after.unwrap_or_else(|| self.backoff(retry))
+    }
+
+    /// Exponential backoff with "equal jitter": half of the backoff is fixed, the other half random.
+    fn backoff(&self, retry: u32) -> Duration {
+        let exp = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(retry.saturating_sub(1)))
+            .min(self.max_backoff);
+
+        exp / 2 + (exp / 2).mul_f64(jitter())
+    }
+}
+
+/// Reads a `Retry-After` header given in seconds. IMDS doesn't send the HTTP-date form, so that is ignored.
+pub fn retry_after(res: &Response) -> Option<Duration> {
+    res.headers()
+        .get(RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .map(Duration::from_secs)
+}
+
+/// A random fraction in `[0, 1)`, seeded from the per-process random keys the standard library uses for hash maps.
+fn jitter() -> f64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(0);
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn backoff_should_grow_with_jitter_up_to_the_cap() {
+        let policy = RetryPolicy {
+            initial_backoff: Duration::from_secs(2),
+            max_backoff: Duration::from_secs(10),
+            ..Default::default()
+        };
+
+        for _ in 0..50 {
+            let first = policy.delay(1, None);
+            assert!(first >= Duration::from_secs(1) && first <= Duration::from_secs(2), "{:?}", first);
+
+            let third = policy.delay(3, None);
+            assert!(third >= Duration::from_secs(4) && third <= Duration::from_secs(8), "{:?}", third);
+
+            let capped = policy.delay(30, None);
+            assert!(capped >= Duration::from_secs(5) && capped <= Duration::from_secs(10), "{:?}", capped);
+        }
+
+        assert_eq!(Duration::from_secs(42), policy.delay(1, Some(Duration::from_secs(42))));
+    }
+}
@@ -1,4 +1,6 @@
 pub mod conncheck;
+pub mod discovery;
 pub mod egress;
 pub mod imds;
+pub mod outcome;
 pub mod telemetry;
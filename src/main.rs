@@ -1,21 +1,48 @@
 use aks_egress_checker::egress::{EgressGroup, EgressRule};
 use aks_egress_checker::{
-    conncheck,
-    egress::{load_egress_data, print_conn_results, EgressData},
+    conncheck::{
+        self, load_trusted_ca, parse_assignment, parse_no_proxy, parse_proxy_url, redact_proxy_url, AuditOptions,
+        ProbeTimeouts, ProxyConfig, ReportHeader, TemplateVars, IMDS_HOST,
+    },
+    egress::{
+        self, load_egress_data, print_conn_results, read_sources, Cloud, DataSource, EgressData, Format,
+        DEFAULT_DATA_DIR, REQUIRED_EGRESS_ONLY,
+    },
+    discovery::{self, ArmLookup, FqdnSources},
+    imds::{self, ImdsUnavailable, InstanceMetadata, RetryPolicy, AZURE_JSON_PATH},
+    outcome::{AuditExitCode, FailOn, RuntimeFailure},
     telemetry::configure_telemetry,
 };
+use anyhow::Context;
 use clap::{builder::PossibleValue, Arg, ArgAction, ArgMatches, Command};
 use tabled::{builder::Builder};
 use tabled::settings::Style;
 
+use std::{
+    path::{Path, PathBuf},
+    process::ExitCode,
+    time::Duration,
+};
+
 #[tokio::main]
-async fn main() -> anyhow::Result<()> {
+async fn main() -> ExitCode {
+    match run().await {
+        Ok(code) => code.into(),
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            AuditExitCode::from_error(&e).into()
+        }
+    }
+}
 
+async fn run() -> anyhow::Result<AuditExitCode> {
     let matches = Command::new("aks-egress")
         .about("AKS egress checker for outbound connectivity")
         .version("0.1.0")
         .author("Adam Margherio")
         .propagate_version(true)
+        .subcommand_required(true)
+        .arg_required_else_help(true)
         .arg(
             Arg::new("loglevel")
                 .long("log-level")
@@ -50,9 +77,40 @@ async fn main() -> anyhow::Result<()> {
                 .required(false)
                 .hide(true)
         )
+        .arg(
+            Arg::new("data-dir")
+                .long("data-dir")
+                .help("Directory to read egress data files from. Can be given more than once.")
+                .long_help("Directory to read egress data files from, reading every .json, .yaml, .yml and .toml file and every file without an extension (such as the keys of a mounted configmap) in name order. Hidden files are skipped. Can be given more than once and combined with --data-file. Defaults to /etc/egress-data when neither is given.")
+                .value_parser(clap::value_parser!(PathBuf))
+                .action(ArgAction::Append)
+                .global(true)
+                .required(false)
+        )
+        .arg(
+            Arg::new("data-file")
+                .long("data-file")
+                .help("Egress data file to read, or '-' for stdin. Can be given more than once.")
+                .long_help("Egress data file to read, or '-' to read from stdin. The file can hold a single egress group or a whole bundle with egressVersion, name and groups. Can be given more than once and combined with --data-dir, files are read after the directories.")
+                .value_parser(clap::value_parser!(PathBuf))
+                .action(ArgAction::Append)
+                .global(true)
+                .required(false)
+        )
+        .arg(
+            Arg::new("overlay")
+                .long("overlay")
+                .help("Overlay file, directory of overlays or '-' for stdin, applied on top of the egress data. Can be given more than once.")
+                .long_help("Overlay file, directory of overlay files (such as a mounted configmap) or '-' for stdin, applied on top of the egress data to add groups, add rules to existing groups, override the dst, port or protocol of rules, or disable rules. Overlays are applied in the order given, and the files in a directory in name order, so later overlays win over earlier ones. Within an overlay, groups are added first, then rules, then overrides are applied, and rules are disabled last.")
+                .value_parser(clap::value_parser!(PathBuf))
+                .action(ArgAction::Append)
+                .global(true)
+                .required(false)
+        )
         .subcommand(
             Command::new("audit")
             .about("Performs an egress audit using the selected policies.")
+            .after_long_help(EXIT_CODE_HELP)
             .arg(
                 Arg::new("egress-groups")
                     .long("group-name")
@@ -62,6 +120,8 @@ async fn main() -> anyhow::Result<()> {
                         "Egress groups to test for. The list of groups can be found using the 'list-groups' command.
                         This can be used multiple times to indicate the set of egress connectivity to check.
                         
+                        If a value of 'required-egress-only' or 'optional-egress-only' is supplied, all of the required or optional groups are checked.
+
                         If not provided, the groups will default to the AKS required egress and will not check any of the cluster extensions or add-ons.")
                     .action(ArgAction::Append)
                     .required(false)
@@ -70,8 +130,193 @@ async fn main() -> anyhow::Result<()> {
                 Arg::new("ccp-fqdn")
                     .long("ccp-fqdn")
                     .help("Fully qualified domain name for the AKS control plane.")
-                    .long_help("The fully qualified domain name for the AKS control plane. This can be found by viewing the properties of your AKS cluster in Azure Portal, by checking the results of `az aks show` for the cluster, or by viewing the kubeconfig for the cluster. If no CCP URL is provided, the check will attempt to use kubernetes.default.svc.cluster.local, although this may lead to incorrect results.")
-                    .required(true)
+                    .long_help("The fully qualified domain name for the AKS control plane. This can be found by viewing the properties of your AKS cluster in Azure Portal, by checking the results of `az aks show` for the cluster, or by viewing the kubeconfig for the cluster. If it isn't given, it is read from KUBERNETES_SERVICE_HOST or the server of a kubeconfig ($KUBECONFIG, ~/.kube/config, then the kubelet and bootstrap kubeconfigs on the node), and finally looked up in Azure Resource Manager if --arm-lookup is set.")
+                    .required(false)
+            )
+            .arg(
+                Arg::new("kubeconfig")
+                    .long("kubeconfig")
+                    .help("Kubeconfig to read the control plane FQDN from, instead of the default locations.")
+                    .value_parser(clap::value_parser!(PathBuf))
+                    .action(ArgAction::Append)
+                    .required(false)
+            )
+            .arg(
+                Arg::new("arm-lookup")
+                    .long("arm-lookup")
+                    .help("Look the control plane FQDN up in Azure Resource Manager if it can't be found locally.")
+                    .long_help("Look the control plane FQDN up in Azure Resource Manager if it can't be found locally. The managed cluster is read with a token for the node's managed identity from IMDS, so the identity needs read access to the cluster. The cluster is found from the tags AKS sets on the node, or can be given with --cluster-resource-id.")
+                    .action(ArgAction::SetTrue)
+            )
+            .arg(
+                Arg::new("arm-endpoint")
+                    .long("arm-endpoint")
+                    .help("Azure Resource Manager endpoint for --arm-lookup. Defaults to the one for the cloud.")
+                    .value_parser(|v: &str| reqwest::Url::parse(v).map_err(|e| format!("'{}' is not a valid URL: {}", v, e)))
+                    .required(false)
+            )
+            .arg(
+                Arg::new("cluster-resource-id")
+                    .long("cluster-resource-id")
+                    .help("Resource ID of the managed cluster for --arm-lookup.")
+                    .required(false)
+            )
+            .arg(
+                Arg::new("identity-client-id")
+                    .long("identity-client-id")
+                    .help("Client ID of the user-assigned managed identity to use for --arm-lookup, such as the kubelet identity.")
+                    .required(false)
+            )
+            .arg(
+                Arg::new("concurrency")
+                    .long("concurrency")
+                    .short('c')
+                    .help("Maximum number of connectivity checks to run at the same time.")
+                    .long_help("Maximum number of connectivity checks to run at the same time, across all of the selected egress groups. Results are always reported in the same order as the groups and rules are defined, regardless of this value. Use a value of 1 to run the checks one at a time.")
+                    .value_parser(clap::value_parser!(u16).range(1..))
+                    .default_value("16")
+                    .required(false)
+            )
+            .arg(
+                Arg::new("connect-timeout")
+                    .long("connect-timeout")
+                    .help("Seconds to wait for a TCP connection to be established.")
+                    .long_help("Seconds to wait for a TCP connection to each resolved address to be established before reporting a connect timeout. Fractional values such as 0.5 are accepted. Individual rules can override this with `timeouts.connectMs` in the egress data.")
+                    .value_parser(parse_timeout)
+                    .default_value("5")
+                    .required(false)
+            )
+            .arg(
+                Arg::new("handshake-timeout")
+                    .long("handshake-timeout")
+                    .help("Seconds to wait for a TLS handshake to complete.")
+                    .long_help("Seconds to wait for a TLS handshake to complete once connected before reporting a handshake timeout. Fractional values such as 0.5 are accepted. Individual rules can override this with `timeouts.handshakeMs` in the egress data.")
+                    .value_parser(parse_timeout)
+                    .default_value("5")
+                    .required(false)
+            )
+            .arg(
+                Arg::new("response-timeout")
+                    .long("response-timeout")
+                    .help("Seconds to wait for an application-layer response.")
+                    .long_help("Seconds to wait for an HTTP response or NTP reply once the request has been sent before reporting a response timeout. Fractional values such as 0.5 are accepted. Individual rules can override this with `timeouts.responseMs` in the egress data.")
+                    .value_parser(parse_timeout)
+                    .default_value("10")
+                    .required(false)
+            )
+            .arg(
+                Arg::new("latency-threshold")
+                    .long("latency-threshold")
+                    .help("Seconds above which a passing check is reported as a warning.")
+                    .long_help("Total time, in seconds, for DNS resolution, connect, TLS handshake and first byte above which a passing check is reported as a warning instead. Fractional values such as 0.25 are accepted. Individual rules can set their own threshold with `latencyThresholdMs` in the egress data. If neither is set, checks are never downgraded for being slow.")
+                    .value_parser(parse_timeout)
+                    .required(false)
+            )
+            .arg(
+                Arg::new("http-proxy")
+                    .long("http-proxy")
+                    .help("Proxy to send plain HTTP checks through.")
+                    .long_help("Proxy URL to send plain HTTP checks through, matching `httpProxy` in the cluster's HTTP proxy configuration. Defaults to the `HTTP_PROXY` environment variable. A bare `host:port` is treated as `http://host:port`.")
+                    .required(false)
+            )
+            .arg(
+                Arg::new("https-proxy")
+                    .long("https-proxy")
+                    .help("Proxy to send HTTPS and TCP checks through.")
+                    .long_help("Proxy URL to send HTTPS and TCP checks through using HTTP CONNECT, matching `httpsProxy` in the cluster's HTTP proxy configuration. Defaults to the `HTTPS_PROXY` environment variable. A bare `host:port` is treated as `http://host:port`.")
+                    .required(false)
+            )
+            .arg(
+                Arg::new("no-proxy")
+                    .long("no-proxy")
+                    .help("Destinations that bypass the proxy.")
+                    .long_help("Comma separated destinations that are reached directly instead of through the proxy, matching `noProxy` in the cluster's HTTP proxy configuration. Entries can be domains (which also match their subdomains), IP addresses, CIDR ranges or `*`. Defaults to the `NO_PROXY` environment variable. This can be used multiple times.")
+                    .action(ArgAction::Append)
+                    .required(false)
+            )
+            .arg(
+                Arg::new("proxy-trusted-ca")
+                    .long("proxy-trusted-ca")
+                    .help("PEM file with additional CA certificates to trust.")
+                    .long_help("Path to a PEM file with CA certificates to trust in addition to the public roots, matching `trustedCa` in the cluster's HTTP proxy configuration. These are used for the connection to an HTTPS proxy and for destinations behind a TLS-inspecting proxy.")
+                    .value_parser(clap::value_parser!(PathBuf))
+                    .required(false)
+            )
+            .arg(
+                Arg::new("cloud")
+                    .long("cloud")
+                    .help("Azure cloud to check the egress for, instead of the one reported by IMDS.")
+                    .long_help("Azure cloud to check the egress for: 'public', 'china' or 'usgov' (the IMDS names such as 'AzureUSGovernmentCloud' work too). By default this comes from `compute.azEnvironment` in the instance metadata, and the public cloud is assumed if that's missing or unknown. Groups tagged with a different cloud are left out unless they are selected by name with --group-name.")
+                    .value_parser(Cloud::parse)
+                    .required(false)
+            )
+            .arg(
+                Arg::new("region")
+                    .long("region")
+                    .help("Azure region the node is in. Skips querying IMDS.")
+                    .long_help("Azure region the node is in, e.g. 'eastus2'. Setting this skips querying IMDS entirely, so the checker can run on a laptop, in a CI runner or in a pod where IMDS is blocked. Combine it with --cloud to pick the cloud, since it can't be detected without IMDS and the public cloud is assumed otherwise.")
+                    .required(false)
+            )
+            .arg(
+                Arg::new("zone")
+                    .long("zone")
+                    .help("Availability zone to show in the report, instead of the one reported by IMDS.")
+                    .required(false)
+            )
+            .arg(
+                Arg::new("no-imds")
+                    .long("no-imds")
+                    .help("Don't query IMDS, read the region and cloud from the cloud provider config instead.")
+                    .long_help("Don't query IMDS, read the region and cloud from the cloud provider config (see --azure-json) instead. Without this, the cloud provider config is only read if IMDS can't be reached.")
+                    .action(ArgAction::SetTrue)
+                    .conflicts_with("region")
+            )
+            .arg(
+                Arg::new("azure-json")
+                    .long("azure-json")
+                    .help("Path to the cloud provider config used when IMDS is unavailable.")
+                    .value_parser(clap::value_parser!(PathBuf))
+                    .default_value(AZURE_JSON_PATH)
+            )
+            .arg(
+                Arg::new("var")
+                    .long("var")
+                    .help("Sets a template variable used in rule destinations, as name=value.")
+                    .long_help("Sets a template variable used in rule destinations, as name=value (for example `--var id=<workspace-id>`). Rule destinations can use {region}, {location}, {ccp-id}, {ccp-fqdn}, {endpoint}, {id}, {spec-url} and {dns-server} (for example `--var dns-server=10.0.0.10` to check a custom DNS server); `_` and `-` are interchangeable in names. The region, location, CCP FQDN and CCP ID are filled in automatically, but values given here take precedence. Rules whose destination still has an unresolved variable are reported as skipped. This can be used multiple times and takes precedence over --vars-file.")
+                    .value_parser(parse_var)
+                    .action(ArgAction::Append)
+                    .required(false)
+            )
+            .arg(
+                Arg::new("vars-file")
+                    .long("vars-file")
+                    .help("File with template variables used in rule destinations.")
+                    .long_help("File with template variables used in rule destinations. Either a JSON object of string values (for files ending in .json) or one name=value pair per line, with lines starting with # ignored.")
+                    .value_parser(clap::value_parser!(PathBuf))
+                    .required(false)
+            )
+            .arg(
+                Arg::new("include-disabled")
+                    .long("include-disabled")
+                    .help("Check disabled groups and rules too.")
+                    .long_help("Check groups and rules that are disabled in the egress data too. Without this, rules in disabled groups are reported as skipped and disabled rules are only counted.")
+                    .action(ArgAction::SetTrue)
+            )
+            .arg(
+                Arg::new("private-cluster")
+                    .long("private-cluster")
+                    .help("Audit a private cluster.")
+                    .long_help("Audit a private cluster. Rules that only public clusters need (those without `requiredPrivate` in the egress data) are reported as skipped, and the control plane FQDN is checked to resolve to an RFC1918 address through the cluster's private DNS zone. The result of that check is reported in the 'private-cluster' group.")
+                    .action(ArgAction::SetTrue)
+            )
+            .arg(
+                Arg::new("fail-on")
+                    .long("fail-on")
+                    .help("Which failures make the audit exit with a non-zero code.")
+                    .long_help("Which failures make the audit exit with a non-zero code. With 'required', only failures in required egress fail the run. With 'optional', failures in optional egress fail the run too. With 'never', the run only fails if the audit could not be carried out. Warnings never fail the run.")
+                    .value_parser(FailOn::parse)
+                    .default_value("required")
+                    .required(false)
             )
         )
         .subcommand(
@@ -93,68 +338,372 @@ async fn main() -> anyhow::Result<()> {
                     .action(ArgAction::Append)
                     .required(false)
             )
+            .arg(
+                Arg::new("cloud")
+                    .long("cloud")
+                    .help("Only list the groups for an Azure cloud.")
+                    .long_help("Only list the groups that apply to an Azure cloud: 'public', 'china' or 'usgov' (the IMDS names such as 'AzureUSGovernmentCloud' work too). Groups that aren't tagged with a cloud apply to all of them.")
+                    .value_parser(Cloud::parse)
+                    .required(false)
+            )
+            .arg(
+                Arg::new("include-disabled")
+                    .long("include-disabled")
+                    .help("List disabled groups and rules too.")
+                    .action(ArgAction::SetTrue)
+            )
+)
+        .subcommand(
+            Command::new("validate")
+                .about("Checks the egress data for problems without running an audit.")
+                .long_about("Checks the egress data for problems without running an audit: files that fail to parse, unknown fields, invalid ports or protocols, duplicate rule or group names and unknown template variables. Every problem is reported with the file, line and column it was found at. Overlays given with --overlay are checked too, including that the groups and rules they change exist. The same checks run whenever the egress data is loaded. Exits with 2 if any problems were found.")
+                .arg(
+                    Arg::new("paths")
+                        .help("Egress data files or directories to check, or '-' for stdin. Defaults to the data the audit reads.")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .action(ArgAction::Append)
+                        .required(false)
+                )
+        )
+        .subcommand(
+            Command::new("convert")
+                .about("Converts an egress data file between JSON, YAML and TOML.")
+                .long_about("Converts an egress data file between JSON, YAML and TOML, printing the result or saving it to the path given with -f/--output-file. A file holding one egress group stays a single group and a bundle stays a bundle. The file is validated first and isn't converted if there is anything wrong with it.")
+                .arg(
+                    Arg::new("path")
+                        .help("Egress data file to convert, or '-' for stdin.")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .required(true)
+                )
+                .arg(
+                    Arg::new("to")
+                        .long("to")
+                        .help("Format to convert to: 'json', 'yaml' or 'toml'.")
+                        .long_help("Format to convert to: 'json', 'yaml' or 'toml'. Defaults to the format of the -f/--output-file extension.")
+                        .value_parser(Format::parse)
+                        .required(false)
+                )
         ).get_matches();
 
     configure_telemetry(&matches); // configure telemetry and logging
 
+    match matches.subcommand() {
+        Some(("validate", sub_matches)) => return validate(sub_matches, &matches),
+        Some(("convert", sub_matches)) => return convert(sub_matches, &matches),
+        _ => {}
+    }
+
     // parse the JSON for the egress rules and filter down to enabled groups
-    let mut egress_data = load_egress_data().await?;
+    let mut egress_data = load_egress_data(&data_sources(&matches), &overlay_sources(&matches)).await?;
 
     log::debug!("{:#?}", matches);
 
     match matches.subcommand() {
         Some(("list-groups", sub_matches)) => {
+            if let Some(groups) = parse_group_args(sub_matches) {
+                egress_data.filter_groups(&groups);
+            }
+            if let Some(cloud) = sub_matches.get_one::<Cloud>("cloud") {
+                egress_data.filter_cloud(*cloud, &[]);
+            }
+            if !sub_matches.get_flag("include-disabled") {
+                egress_data.filter_disabled();
+            }
+
             let out = matches.get_one::<String>("format").unwrap();
 
             match out.as_str() {
                 "table" => print_table_output(&egress_data),
-                "json" => {
-                    let filtered_groups = parse_group_args(sub_matches);
-                    match filtered_groups {
-                        Some(groups) => {
-                            egress_data.filter_groups(&groups);
-
-                            println!("{:#?}", serde_json::to_string(&egress_data))
-                        }
-                        None => println!("{:#?}", serde_json::to_string(&egress_data)?),
-                    }
-                }
+                "json" => println!("{}", serde_json::to_string_pretty(&egress_data)?),
                 &_ => println!("{:#?}", egress_data),
             }
         }
         Some(("audit", sub_matches)) => {
-            if let Some(groups) = parse_group_args(sub_matches) {
-                egress_data.filter_groups(&groups);
+            // default to the egress every cluster needs, leaving add-ons and optional features out
+            let required_only = String::from(REQUIRED_EGRESS_ONLY);
+            let groups = parse_group_args(sub_matches).unwrap_or_else(|| vec![&required_only]);
+            egress_data.filter_groups(&groups);
 
-                let conn_results = conncheck::check_connectivity(
-                    &egress_data.groups,
-                    sub_matches.get_one::<String>("ccp-fqdn").unwrap(),
-                )
-                .await?;
+            let metadata = resolve_metadata(sub_matches).await?;
+            let cloud = match sub_matches.get_one::<Cloud>("cloud") {
+                Some(cloud) => *cloud,
+                None => detect_cloud(metadata.compute.az_environment.as_deref()),
+            };
+            egress_data.filter_cloud(cloud, &groups);
 
-                print_conn_results(&conn_results, &sub_matches).await;
-            } else {
-                let conn_results = conncheck::check_connectivity(
-                    &egress_data.groups,
-                    sub_matches.get_one::<String>("ccp-fqdn").unwrap(),
-                )
-                .await?;
+            let ccp_fqdn = resolve_ccp_fqdn(sub_matches, &metadata, cloud).await?;
 
-                print_conn_results(&conn_results, &sub_matches).await;
-            }
+            let options = AuditOptions {
+                concurrency: *sub_matches.get_one::<u16>("concurrency").unwrap() as usize,
+                timeouts: ProbeTimeouts {
+                    connect: *sub_matches.get_one::<Duration>("connect-timeout").unwrap(),
+                    handshake: *sub_matches.get_one::<Duration>("handshake-timeout").unwrap(),
+                    response: *sub_matches.get_one::<Duration>("response-timeout").unwrap(),
+                },
+                latency_threshold: sub_matches.get_one::<Duration>("latency-threshold").copied(),
+                proxy: parse_proxy_args(sub_matches)?,
+                include_disabled: sub_matches.get_flag("include-disabled"),
+                private_cluster: sub_matches.get_flag("private-cluster"),
+                vars: parse_var_args(sub_matches)?,
+            };
+
+            let conn_results = conncheck::check_connectivity(
+                &egress_data.groups,
+                &ccp_fqdn,
+                &metadata,
+                &options,
+            )
+            .await?;
+
+            print_conn_results(&ReportHeader::from(&metadata), &conn_results, &matches).await?;
+
+            let fail_on = *sub_matches.get_one::<FailOn>("fail-on").unwrap();
+            return Ok(AuditExitCode::from_results(&conn_results, fail_on));
+        }
+        // clap rejects a missing or unknown subcommand before we get here
+        _ => unreachable!("every subcommand is handled above"),
+    }
+
+    Ok(AuditExitCode::Passed)
+}
+
+/// The egress data sources from `--data-dir` and `--data-file`, directories first, falling back to the default
+/// data directory when neither is given.
+fn data_sources(matches: &ArgMatches) -> Vec<DataSource> {
+    let dirs = matches.get_many::<PathBuf>("data-dir").into_iter().flatten();
+    let files = matches.get_many::<PathBuf>("data-file").into_iter().flatten();
+
+    let sources: Vec<DataSource> = dirs
+        .map(|d| DataSource::Dir(d.clone()))
+        .chain(files.map(|f| match f.to_str() {
+            Some("-") => DataSource::Stdin,
+            _ => DataSource::File(f.clone()),
+        }))
+        .collect();
+
+    match sources.is_empty() {
+        true => vec![DataSource::Dir(PathBuf::from(DEFAULT_DATA_DIR))],
+        false => sources,
+    }
+}
+
+/// The overlays from `--overlay`, in the order they were given.
+fn overlay_sources(matches: &ArgMatches) -> Vec<DataSource> {
+    matches
+        .get_many::<PathBuf>("overlay")
+        .into_iter()
+        .flatten()
+        .map(|p| DataSource::from_path(p))
+        .collect()
+}
+
+/// Checks the egress data and prints every problem found, one per line or as JSON with `-o json`.
+fn validate(sub_matches: &ArgMatches, matches: &ArgMatches) -> anyhow::Result<AuditExitCode> {
+    let sources: Vec<DataSource> = match sub_matches.get_many::<PathBuf>("paths") {
+        Some(paths) => paths.map(|p| DataSource::from_path(p)).collect(),
+        None => data_sources(matches),
+    };
+    let loaded = read_sources(&sources, &overlay_sources(matches))?;
+
+    match matches.get_one::<String>("format").map(|f| f.as_str()) {
+        Some("json") => println!(
+            "{}",
+            serde_json::to_string_pretty(&serde_json::json!({
+                "files": loaded.files,
+                "diagnostics": loaded.diagnostics,
+            }))?
+        ),
+        _ => {
+            loaded.diagnostics.iter().for_each(|d| println!("{}", d));
+            println!("Checked {} file(s), found {} problem(s).", loaded.files, loaded.diagnostics.len());
+        }
+    }
+
+    match loaded.diagnostics.is_empty() {
+        true => Ok(AuditExitCode::Passed),
+        false => Ok(AuditExitCode::ConfigError),
+    }
+}
+
+/// Converts an egress data file to another format, printing it or saving it to `-f/--output-file`.
+fn convert(sub_matches: &ArgMatches, matches: &ArgMatches) -> anyhow::Result<AuditExitCode> {
+    let source = DataSource::from_path(sub_matches.get_one::<PathBuf>("path").unwrap());
+    let output_file = matches.get_one::<String>("output-file-path");
+
+    let to = match sub_matches.get_one::<Format>("to") {
+        Some(to) => *to,
+        None => output_file
+            .and_then(|path| Format::from_extension(Path::new(path)))
+            .context("no format to convert to, give one with --to or an output file ending in .json, .yaml or .toml")?,
+    };
+
+    let converted = egress::convert(&source, to)?;
+    match output_file {
+        Some(path) => {
+            std::fs::write(path, converted).with_context(|| RuntimeFailure(format!("failed to write {}", path)))?;
+            log::info!("Converted {} to {} in {}", source, to, path);
+        }
+        None => print!("{}", converted),
+    }
+
+    Ok(AuditExitCode::Passed)
+}
+
+const EXIT_CODE_HELP: &str = "Exit codes:
+  0  Every rule covered by --fail-on passed
+  1  At least one required rule failed
+  2  Configuration or egress data error, including invalid arguments
+  3  Only optional rules failed (only with --fail-on optional)
+  4  The instance metadata could not be retrieved from IMDS
+  5  The audit failed on the node: DNS setup, control plane FQDN lookup or writing the results";
+
+/// Parses a timeout given in (possibly fractional) seconds.
+fn parse_timeout(val: &str) -> Result<Duration, String> {
+    match val.trim().parse::<f64>() {
+        Ok(secs) if secs.is_finite() && secs > 0.0 => Ok(Duration::from_secs_f64(secs)),
+        _ => Err(format!("'{}' is not a positive number of seconds", val)),
+    }
+}
+
+/// Works out the instance metadata for the audit: from the command line if `--region` is given, from the cloud
+/// provider config if `--no-imds` is, and otherwise from IMDS with the cloud provider config as a fallback. The
+/// `--cloud` and `--zone` overrides are applied on top of whichever source was used.
+async fn resolve_metadata(sub_matches: &ArgMatches) -> anyhow::Result<InstanceMetadata> {
+    let azure_json = sub_matches.get_one::<PathBuf>("azure-json").unwrap();
+
+    let mut metadata = match sub_matches.get_one::<String>("region") {
+        Some(region) => {
+            log::info!("Using region {} from the command line, IMDS won't be queried", region);
+            let mut metadata = InstanceMetadata::default();
+            metadata.compute.location = region.clone();
+            metadata
+        }
+        None if sub_matches.get_flag("no-imds") => imds::metadata_from_azure_json(azure_json)?,
+        None => imds::get_instance_metadata_or_fallback(IMDS_HOST, azure_json)
+            .await
+            .context(ImdsUnavailable)?,
+    };
+
+    if let Some(cloud) = sub_matches.get_one::<Cloud>("cloud") {
+        metadata.compute.az_environment = Some(cloud.to_string());
+    }
+    if let Some(zone) = sub_matches.get_one::<String>("zone") {
+        metadata.compute.zone = zone.clone();
+    }
+
+    Ok(metadata)
+}
+
+/// Uses `--ccp-fqdn` if it was given, otherwise tries to find the control plane FQDN on the node.
+async fn resolve_ccp_fqdn(
+    sub_matches: &ArgMatches,
+    metadata: &InstanceMetadata,
+    cloud: Cloud,
+) -> anyhow::Result<String> {
+    if let Some(ccp_fqdn) = sub_matches.get_one::<String>("ccp-fqdn") {
+        return Ok(ccp_fqdn.clone());
+    }
+
+    let mut sources = FqdnSources::from_env();
+    if let Some(kubeconfigs) = sub_matches.get_many::<PathBuf>("kubeconfig") {
+        sources.kubeconfigs = kubeconfigs.cloned().collect();
+    }
+    if sub_matches.get_flag("arm-lookup") {
+        let resource = cloud.resource_manager_endpoint();
+        sources.arm = Some(ArmLookup {
+            endpoint: match sub_matches.get_one::<reqwest::Url>("arm-endpoint") {
+                Some(endpoint) => endpoint.clone(),
+                None => reqwest::Url::parse(resource)?,
+            },
+            resource: resource.to_string(),
+            imds_host: IMDS_HOST.to_string(),
+            client_id: sub_matches.get_one::<String>("identity-client-id").cloned(),
+            cluster_resource_id: sub_matches.get_one::<String>("cluster-resource-id").cloned(),
+            retry_policy: RetryPolicy::default(),
+        });
+    }
+
+    discovery::discover_ccp_fqdn(&sources, metadata)
+        .await
+        .with_context(|| RuntimeFailure(String::from("failed to find the control plane FQDN")))
+}
+
+/// Maps the IMDS `azEnvironment` onto a cloud, falling back to the public cloud if it's missing or isn't one we have
+/// egress data for, so the sovereign cloud endpoints are only checked when asked for.
+fn detect_cloud(az_environment: Option<&str>) -> Cloud {
+    match az_environment.map(Cloud::parse) {
+        Some(Ok(cloud)) => {
+            log::info!("Using the egress groups for {} based on the instance metadata", cloud);
+            cloud
         }
-        Some((&_, _)) => {
-            unimplemented!()
+        Some(Err(_)) => {
+            log::warn!(
+                "IMDS reported the cloud environment as '{}', which has no egress data of its own. Assuming {}, use --cloud to pick another cloud.",
+                az_environment.unwrap_or_default(),
+                Cloud::AzurePublicCloud
+            );
+            Cloud::AzurePublicCloud
         }
         None => {
-            unimplemented!()
+            // e.g. with --region, where IMDS isn't queried, or a cloud provider config without a cloud
+            log::warn!(
+                "No cloud environment was reported for the node. Assuming {}, use --cloud to pick another cloud.",
+                Cloud::AzurePublicCloud
+            );
+            Cloud::AzurePublicCloud
         }
     }
+}
+
+/// Parses a `--var` value into a variable name and value.
+fn parse_var(val: &str) -> Result<(String, String), String> {
+    parse_assignment(val).map_err(|e| e.to_string())
+}
+
+/// Collects the template variables from `--vars-file` and `--var`, with `--var` taking precedence.
+fn parse_var_args(sm: &ArgMatches) -> anyhow::Result<TemplateVars> {
+    let mut vars = match sm.get_one::<PathBuf>("vars-file") {
+        Some(path) => TemplateVars::from_file(path)?,
+        None => TemplateVars::new(),
+    };
+
+    if let Some(assignments) = sm.get_many::<(String, String)>("var") {
+        assignments.for_each(|(name, value)| vars.set(name, value.as_str()));
+    }
 
-    Ok(())
+    Ok(vars)
 }
 
-fn parse_group_args<'a>(sm: &'a ArgMatches) -> Option<Vec<&String>> {
+/// Builds the proxy configuration from the environment, with any proxy flags taking precedence.
+fn parse_proxy_args(sm: &ArgMatches) -> anyhow::Result<ProxyConfig> {
+    let mut proxy = ProxyConfig::from_env()?;
+
+    if let Some(url) = sm.get_one::<String>("http-proxy") {
+        proxy.http_proxy = Some(parse_proxy_url(url)?);
+    }
+    if let Some(url) = sm.get_one::<String>("https-proxy") {
+        proxy.https_proxy = Some(parse_proxy_url(url)?);
+    }
+    if let Some(entries) = sm.get_many::<String>("no-proxy") {
+        proxy.no_proxy = entries.flat_map(|e| parse_no_proxy(e)).collect();
+    }
+    if let Some(path) = sm.get_one::<PathBuf>("proxy-trusted-ca") {
+        proxy.trusted_ca = load_trusted_ca(path)?;
+    }
+
+    if !proxy.is_empty() {
+        log::info!(
+            "Using HTTP proxy {:?}, HTTPS proxy {:?}, no proxy for {:?}",
+            proxy.http_proxy.as_ref().map(redact_proxy_url),
+            proxy.https_proxy.as_ref().map(redact_proxy_url),
+            proxy.no_proxy
+        );
+    }
+
+    Ok(proxy)
+}
+
+fn parse_group_args(sm: &ArgMatches) -> Option<Vec<&String>> {
     let mut group_names: Vec<&String> = Vec::new();
 
     match sm.get_many::<String>("egress-groups") {
@@ -174,40 +723,41 @@ fn print_table_output(egress_data: &EgressData) {
         "Destination",
         "Port",
         "Protocol",
+        "Required group?",
         "Required for private clusters?",
         "Enabled for checking?",
+        "Source",
     ];
     builder.set_header(columns.clone());
 
-    // for each set of egress groups, grab some basics and then iterate the rules to print each one
+    // for each set of egress groups, grab some basics and then iterate the rules to print each one.
+    // disabled groups and rules have already been filtered out unless they were asked for.
     egress_data.groups.iter().for_each(|g: &EgressGroup| {
         g.rules
             .iter()
-            .filter(|r| r.rule_enabled)
             .for_each(|r: &EgressRule| {
-                let mut enabled = String::new();
-                let mut required_private = String::new();
-
-                if r.rule_enabled {
-                    enabled = String::from("Yes");
+                let enabled = if g.enabled && r.rule_enabled {
+                    String::from("Yes")
                 } else {
-                    enabled = String::from("No");
-                }
+                    String::from("No")
+                };
 
-                if r.required_private {
-                    required_private = String::from("Yes");
+                let required_private = if r.required_private {
+                    String::from("Yes")
                 } else {
-                    required_private = String::from("No");
-                }
+                    String::from("No")
+                };
 
                 builder.push_record(vec![
                     g.name.clone(),
                     r.name.clone(),
                     r.dst.clone(),
-                    r.port.clone(),
-                    r.protocol.clone(),
+                    r.port.to_string(),
+                    r.protocol.to_string(),
+                    if g.required_group { String::from("Yes") } else { String::from("No") },
                     required_private.clone(),
                     enabled.clone(),
+                    r.origin.to_string(),
                 ]);
             });
     });
@@ -0,0 +1,165 @@
+use std::process::ExitCode;
+
+use crate::{
+    conncheck::{ConnCheckResult, EgressGroupResult},
+    imds::ImdsUnavailable,
+};
+
+/// Exit codes for the process, so CI pipelines and Kubernetes Jobs can act on the outcome of an audit.
+///
+/// | Code | Meaning                                                                          |
+/// |------|----------------------------------------------------------------------------------|
+/// | 0    | Every rule that `--fail-on` covers passed                                        |
+/// | 1    | At least one required rule failed                                                |
+/// | 2    | Configuration or egress data error, including invalid command line arguments     |
+/// | 3    | Only optional rules failed (reported only with `--fail-on optional`)             |
+/// | 4    | The instance metadata could not be retrieved from IMDS                           |
+/// | 5    | The audit failed on the node: DNS setup, FQDN lookup or writing the results      |
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditExitCode {
+    Passed = 0,
+    RequiredFailed = 1,
+    ConfigError = 2,
+    OptionalFailed = 3,
+    ImdsFailure = 4,
+    RuntimeFailure = 5,
+}
+
+/// Attached as context to errors from the node the audit runs on, rather than from its configuration or egress data,
+/// such as failing to set up the DNS resolver, find the control plane FQDN or write the results. The message describes
+/// what failed.
+#[derive(Debug)]
+pub struct RuntimeFailure(pub String);
+
+impl std::fmt::Display for RuntimeFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Which failures make the run exit with a non-zero code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FailOn {
+    /// Only failures in required rules fail the run.
+    #[default]
+    Required,
+    /// Failures in any rule fail the run.
+    Optional,
+    /// The run only fails if the audit itself couldn't be carried out.
+    Never,
+}
+
+impl FailOn {
+    /// Parses the value of the `--fail-on` flag.
+    pub fn parse(val: &str) -> Result<Self, String> {
+        match val.trim().to_ascii_lowercase().as_str() {
+            "required" => Ok(FailOn::Required),
+            "optional" => Ok(FailOn::Optional),
+            "never" => Ok(FailOn::Never),
+            _ => Err(format!("'{}' is not one of 'required', 'optional' or 'never'", val)),
+        }
+    }
+}
+
+impl AuditExitCode {
+    /// Picks the exit code for a completed audit.
+    ///
+    /// Warnings never fail the run since the destination was reachable.
+    pub fn from_results(results: &[EgressGroupResult], fail_on: FailOn) -> Self {
+        let failed = |required: bool| {
+            results
+                .iter()
+                .filter(|g| g.required == required)
+                .any(|g| g.rule_results.iter().any(|r| r.result == ConnCheckResult::Fail))
+        };
+
+        match fail_on {
+            FailOn::Never => AuditExitCode::Passed,
+            _ if failed(true) => AuditExitCode::RequiredFailed,
+            FailOn::Optional if failed(false) => AuditExitCode::OptionalFailed,
+            _ => AuditExitCode::Passed,
+        }
+    }
+
+    /// Picks the exit code for an error that stopped the audit from running.
+    pub fn from_error(err: &anyhow::Error) -> Self {
+        if err.downcast_ref::<ImdsUnavailable>().is_some() {
+            AuditExitCode::ImdsFailure
+        } else if err.downcast_ref::<RuntimeFailure>().is_some() {
+            AuditExitCode::RuntimeFailure
+        } else {
+            AuditExitCode::ConfigError
+        }
+    }
+}
+
+impl From<AuditExitCode> for ExitCode {
+    fn from(code: AuditExitCode) -> Self {
+        ExitCode::from(code as u8)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::conncheck::{EgressRuleResult, RuleCounts};
+    use anyhow::{anyhow, Context};
+
+    fn group(required: bool, results: &[ConnCheckResult]) -> EgressGroupResult {
+        EgressGroupResult {
+            name: String::from("group"),
+            enabled: true,
+            required,
+            counts: RuleCounts::default(),
+            rule_results: results
+                .iter()
+                .map(|result| EgressRuleResult {
+                    result: *result,
+                    ..EgressRuleResult::pass("rule")
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn exit_code_should_follow_fail_on() {
+        let all_passed = vec![
+            group(true, &[ConnCheckResult::Pass, ConnCheckResult::Warn]),
+            group(false, &[ConnCheckResult::Pass]),
+        ];
+        let optional_failed = vec![
+            group(true, &[ConnCheckResult::Pass]),
+            group(false, &[ConnCheckResult::Fail]),
+        ];
+        let required_failed = vec![
+            group(true, &[ConnCheckResult::Fail]),
+            group(false, &[ConnCheckResult::Fail]),
+        ];
+
+        assert_eq!(AuditExitCode::Passed, AuditExitCode::from_results(&all_passed, FailOn::Optional));
+        assert_eq!(AuditExitCode::Passed, AuditExitCode::from_results(&optional_failed, FailOn::Required));
+        assert_eq!(
+            AuditExitCode::OptionalFailed,
+            AuditExitCode::from_results(&optional_failed, FailOn::Optional)
+        );
+        assert_eq!(
+            AuditExitCode::RequiredFailed,
+            AuditExitCode::from_results(&required_failed, FailOn::Optional)
+        );
+        assert_eq!(AuditExitCode::Passed, AuditExitCode::from_results(&required_failed, FailOn::Never));
+    }
+
+    #[test]
+    fn imds_errors_should_be_told_apart() {
+        let imds_err = Err::<(), _>(anyhow!("connection refused")).context(ImdsUnavailable).unwrap_err();
+
+        assert_eq!(AuditExitCode::ImdsFailure, AuditExitCode::from_error(&imds_err));
+        assert_eq!(AuditExitCode::ConfigError, AuditExitCode::from_error(&anyhow!("bad data")));
+        let write_err = Err::<(), _>(anyhow!("permission denied"))
+            .with_context(|| RuntimeFailure(String::from("failed to write results to out.json")))
+            .unwrap_err();
+        assert_eq!(AuditExitCode::RuntimeFailure, AuditExitCode::from_error(&write_err));
+        assert!(FailOn::parse("sometimes").is_err());
+        assert_eq!(Ok(FailOn::Optional), FailOn::parse("Optional"));
+    }
+}
@@ -1,5 +1,5 @@
 use clap::ArgMatches;
-use tracing_subscriber::{filter::EnvFilter, fmt, Registry, prelude::*};
+use tracing_subscriber::{filter::EnvFilter, fmt, prelude::*};
 
 use std::env;
 use tracing_subscriber::layer::SubscriberExt;
@@ -16,7 +16,11 @@ pub fn configure_telemetry(matches: &ArgMatches) {
         env::set_var("RUST_LOG", "info")
     }
 
-    // Initialize the log tracer and the global subscriber.
+    // Initialize the log tracer and the global subscriber. Logs go to stderr so stdout only carries the report,
+    // e.g. for `-o json audit > out.json`.
     let env_filter = EnvFilter::from_default_env();
-    tracing_subscriber::registry().with(fmt::layer()).with(env_filter).init();
+    tracing_subscriber::registry()
+        .with(fmt::layer().with_writer(std::io::stderr))
+        .with(env_filter)
+        .init();
 }